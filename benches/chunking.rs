@@ -0,0 +1,190 @@
+//! Compares chunking and packing through the copying iterator with the
+//! zero-copy path backups use, which borrows chunks from the chunker and
+//! compresses and seals them straight into the pack buffer, and finding cut
+//! points with the scalar gear hash against the SIMD one.
+
+use std::hint::black_box;
+use std::io::Read;
+
+use casb::chunker::Chunker;
+use casb::fastcdc::{self, Gear, MASKS, StreamCDC};
+use casb::pack::{self, Compression, Packer};
+use casb::repo::{BlobClass, ChunkerParams, Cipher, Key, PackSize, Subkeys};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+const SOURCE_SIZE: usize = 32 * 1024 * 1024;
+const SMALL_SOURCES: usize = 4096;
+const SMALL_SOURCE_SIZE: usize = 4096;
+
+/// Half random and half repetitive, so some chunks compress and some don't.
+fn source(size: usize) -> Vec<u8> {
+    let mut data = vec![0; size];
+    blake3::Hasher::new().finalize_xof().fill(&mut data);
+
+    let text = b"fn main() { println!(\"hello\"); }\n";
+    for block in data.chunks_mut(64 * 1024).skip(1).step_by(2) {
+        for (i, byte) in block.iter_mut().enumerate() {
+            *byte = text[i % text.len()];
+        }
+    }
+
+    data
+}
+
+struct Setup {
+    key: Key,
+    subkeys: Subkeys,
+    chunker: Box<dyn Chunker>,
+}
+
+impl Setup {
+    fn new() -> Self {
+        let key = Key { bytes: [7; 32] };
+        Self {
+            key,
+            subkeys: Subkeys::derive(&key),
+            chunker: pack::chunker(&ChunkerParams::default(), &[9; 32]),
+        }
+    }
+
+    fn packer(&self) -> Packer {
+        Packer::new(self.key, Cipher::ChaCha12Blake3, PackSize::default())
+    }
+
+    /// Splits the sources with [`pack::split_to_data_blobs`] and adds the
+    /// encoded blobs to a packer.
+    fn iterator(&self, sources: &[&[u8]]) -> usize {
+        let mut packer = self.packer();
+        let mut packs = 0;
+
+        for source in sources {
+            let mut reader: &[u8] = source;
+            for (entry, data) in pack::split_to_data_blobs(
+                &mut reader as &mut dyn Read,
+                self.chunker.as_ref(),
+                Compression::Auto,
+                None,
+                &self.subkeys,
+            ) {
+                if packer.add_blob(entry, &data).is_err() {
+                    black_box(packer.finish());
+                    packs += 1;
+                    packer.add_blob(entry, &data).unwrap();
+                }
+            }
+        }
+
+        black_box(packer.finish());
+        packs + 1
+    }
+
+    /// Borrows chunks from one reused chunker buffer and packs them with
+    /// [`Packer::pack_blob`].
+    fn zero_copy(&self, sources: &[&[u8]]) -> usize {
+        let mut packer = self.packer();
+        let mut packs = 0;
+        let mut buffer = Vec::new();
+
+        for source in sources {
+            let mut chunker =
+                StreamCDC::with_chunker(*source, self.chunker.as_ref()).with_buffer(buffer);
+
+            while let Some(chunk) = chunker.next_chunk() {
+                let data = chunk.unwrap().data;
+                let id = self.subkeys.blob_id(data);
+                let pack_blob = |packer: &mut Packer| {
+                    packer.pack_blob(id, BlobClass::Data, data, Compression::Auto, None)
+                };
+
+                if pack_blob(&mut packer).is_err() {
+                    black_box(packer.finish());
+                    packs += 1;
+                    pack_blob(&mut packer).unwrap();
+                }
+            }
+
+            buffer = chunker.into_buffer();
+        }
+
+        black_box(packer.finish());
+        packs + 1
+    }
+}
+
+fn bench_large_source(c: &mut Criterion) {
+    let setup = Setup::new();
+    let source = source(SOURCE_SIZE);
+    let sources = [&source[..]];
+
+    let mut group = c.benchmark_group("chunk_and_pack_large");
+    group.throughput(Throughput::Bytes(SOURCE_SIZE as u64));
+    group.sample_size(10);
+    group.bench_function("iterator", |b| b.iter(|| setup.iterator(&sources)));
+    group.bench_function("zero_copy", |b| b.iter(|| setup.zero_copy(&sources)));
+    group.finish();
+}
+
+fn bench_small_sources(c: &mut Criterion) {
+    let setup = Setup::new();
+    let source = source(SMALL_SOURCES * SMALL_SOURCE_SIZE);
+    let sources = source.chunks(SMALL_SOURCE_SIZE).collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("chunk_and_pack_small");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.sample_size(10);
+    group.bench_function("iterator", |b| b.iter(|| setup.iterator(&sources)));
+    group.bench_function("zero_copy", |b| b.iter(|| setup.zero_copy(&sources)));
+    group.finish();
+}
+
+type Cut = fn(&[u8], usize, usize, usize, u64, u64, u64, u64, &Gear) -> (u64, usize);
+
+/// Splits the whole source with the given cut function at the default chunk
+/// sizes and normalization, returning the number of chunks.
+fn split(source: &[u8], gear: &Gear, cut: Cut) -> usize {
+    let params = ChunkerParams::default();
+    let bits = params.avg_size.ilog2() as usize;
+    let (mask_s, mask_l) = (MASKS[bits + 1], MASKS[bits - 1]);
+
+    let mut offset = 0;
+    let mut chunks = 0;
+    while offset < source.len() {
+        let (hash, length) = cut(
+            &source[offset..],
+            params.min_size as usize,
+            params.avg_size as usize,
+            params.max_size as usize,
+            mask_s,
+            mask_l,
+            mask_s << 1,
+            mask_l << 1,
+            gear,
+        );
+        black_box(hash);
+        offset += length;
+        chunks += 1;
+    }
+
+    chunks
+}
+
+fn bench_cut(c: &mut Criterion) {
+    let gear = Gear::permuted(&[9; 32]);
+    let source = source(SOURCE_SIZE);
+
+    let mut group = c.benchmark_group("gear_cut");
+    group.throughput(Throughput::Bytes(SOURCE_SIZE as u64));
+    group.sample_size(20);
+
+    for (name, cut) in [
+        ("scalar", fastcdc::cut_scalar as Cut),
+        ("simd", fastcdc::cut as Cut),
+    ] {
+        group.bench_function(name, |b| b.iter(|| split(&source, &gear, cut)));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_large_source, bench_small_sources, bench_cut);
+criterion_main!(benches);
@@ -0,0 +1,142 @@
+//! Backs up a synthetic source tree of many tiny files, the worst case for
+//! per-blob overhead, with and without inlining small files in their trees.
+
+use std::fs;
+use std::path::Path;
+
+use casb::cmd::backup::{self, Options};
+use casb::pack::{self, Compression, Packer};
+use casb::repo::{BlobClass, ChunkerParams, Cipher, Hash, Key, PackSize, Repository};
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use tempfile::TempDir;
+
+const DIRS: usize = 50;
+const FILES_PER_DIR: usize = 100;
+const FILE_SIZE_MAX: usize = 400;
+
+const WORDS: &[&str] = &[
+    "fn", "let", "mut", "self", "impl", "pub", "use", "struct", "match", "return", "{", "}", ";",
+    "\n",
+];
+
+/// Writes the fixture, returning its directory and the total size of its files.
+fn fixture() -> (TempDir, u64) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = 0x2545f491u32;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as usize
+    };
+
+    let mut total = 0;
+    for d in 0..DIRS {
+        let path = dir.path().join(format!("module{}", d));
+        fs::create_dir(&path).unwrap();
+
+        for f in 0..FILES_PER_DIR {
+            let size = next() % FILE_SIZE_MAX;
+            let mut content = String::new();
+            while content.len() < size {
+                content.push_str(WORDS[next() % WORDS.len()]);
+                content.push(' ');
+            }
+
+            fs::write(path.join(format!("file{}.rs", f)), &content).unwrap();
+            total += content.len() as u64;
+        }
+    }
+
+    (dir, total)
+}
+
+fn new_repository() -> (TempDir, Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repository = Repository::init(
+        &dir.path().join("repo"),
+        Key::generate(),
+        Cipher::ChaCha12Blake3,
+        ChunkerParams::default(),
+        PackSize::default(),
+        None,
+    );
+
+    (dir, repository)
+}
+
+fn repository_size(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+fn bench_backup(c: &mut Criterion) {
+    let (source, total) = fixture();
+    let mut group = c.benchmark_group("backup_small_files");
+    group.throughput(Throughput::Bytes(total));
+    group.sample_size(10);
+
+    for inline_size in [0, 128, 512] {
+        let options = Options {
+            compression: Compression::Auto,
+            inline_size,
+            deterministic: false,
+        };
+
+        let (dir, repository) = new_repository();
+        backup::run(&repository, source.path(), options);
+        println!(
+            "inline size {}: {} bytes of files stored in {} bytes",
+            inline_size,
+            total,
+            repository_size(dir.path())
+        );
+
+        group.bench_function(format!("inline_{}", inline_size), |b| {
+            b.iter_batched(
+                new_repository,
+                |(_dir, repository)| backup::run(&repository, source.path(), options),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_packer(c: &mut Criterion) {
+    let blobs = (0..10_000u32)
+        .map(|i| {
+            let data = format!("tiny blob number {}", i).into_bytes();
+            let id = Hash::from(blake3::hash(&data));
+            pack::encode_blob(id, BlobClass::Data, data, Compression::Auto, None)
+        })
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("packer_tiny_blobs");
+    group.throughput(Throughput::Elements(blobs.len() as u64));
+
+    group.bench_function("add_blob", |b| {
+        b.iter(|| {
+            let mut packer = Packer::new(
+                Key { bytes: [7; 32] },
+                Cipher::ChaCha12Blake3,
+                PackSize::default(),
+            );
+            for (entry, data) in &blobs {
+                packer.add_blob(*entry, data).unwrap();
+            }
+
+            packer.finish()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_backup, bench_packer);
+criterion_main!(benches);
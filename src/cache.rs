@@ -15,6 +15,8 @@ pub struct Cache {
 }
 
 impl Cache {
+    // Opens a database file, which is more than a default should do.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let conn = Connection::open(CACHE_PATH).unwrap();
         conn.execute(CACHE_SCHEMA, []).unwrap();
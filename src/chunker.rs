@@ -0,0 +1,383 @@
+//! Algorithms files are split into chunks with, chosen per repository by the
+//! [`ChunkerAlgorithm`] in its config. Each finds the end of the next chunk at
+//! the start of a buffer, which [`StreamCDC`](crate::fastcdc::StreamCDC) fills
+//! from the file being chunked.
+
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::fastcdc::{self, Gear, MASKS, Normalization};
+use crate::repo::ChunkerAlgorithm;
+
+/// Largest number of bytes the buzhash is rolled over, as borg does.
+const BUZHASH_WINDOW: usize = 4095;
+
+pub trait Chunker {
+    /// Finds the end of the chunk at the start of `source`, which holds at
+    /// least [`max_size`](Chunker::max_size) bytes unless the input ends
+    /// sooner. Returns the hash at the cut point, zero if none was needed, and
+    /// the length of the chunk.
+    fn cut(&self, source: &[u8]) -> (u64, usize);
+
+    /// Size no chunk grows beyond.
+    fn max_size(&self) -> usize;
+}
+
+impl<C: Chunker + ?Sized> Chunker for &C {
+    fn cut(&self, source: &[u8]) -> (u64, usize) {
+        (**self).cut(source)
+    }
+
+    fn max_size(&self) -> usize {
+        (**self).max_size()
+    }
+}
+
+impl<C: Chunker + ?Sized> Chunker for Rc<C> {
+    fn cut(&self, source: &[u8]) -> (u64, usize) {
+        (**self).cut(source)
+    }
+
+    fn max_size(&self) -> usize {
+        (**self).max_size()
+    }
+}
+
+/// Panics unless the sizes are within the bounds FastCDC supports, and returns
+/// the small and large masks for the normalization level.
+fn masks(min_size: u32, avg_size: u32, max_size: u32, level: Normalization) -> (u64, u64) {
+    assert!((fastcdc::MINIMUM_MIN..=fastcdc::MINIMUM_MAX).contains(&min_size));
+    assert!((fastcdc::AVERAGE_MIN..=fastcdc::AVERAGE_MAX).contains(&avg_size));
+    assert!((fastcdc::MAXIMUM_MIN..=fastcdc::MAXIMUM_MAX).contains(&max_size));
+    let bits = fastcdc::logarithm2(avg_size);
+    let normalization = level.bits();
+    (
+        MASKS[(bits + normalization) as usize],
+        MASKS[(bits - normalization) as usize],
+    )
+}
+
+/// FastCDC as of 2020, which rolls the gear hash two bytes at a time.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FastCdc2020 {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    mask_s: u64,
+    mask_l: u64,
+    gear: Gear,
+}
+
+impl FastCdc2020 {
+    pub fn new(min_size: u32, avg_size: u32, max_size: u32, level: Normalization) -> Self {
+        let (mask_s, mask_l) = masks(min_size, avg_size, max_size, level);
+        Self {
+            min_size: min_size as usize,
+            avg_size: avg_size as usize,
+            max_size: max_size as usize,
+            mask_s,
+            mask_l,
+            gear: Gear::CANONICAL,
+        }
+    }
+
+    /// Use the given gear tables rather than the canonical ones.
+    pub fn with_gear(mut self, gear: Gear) -> Self {
+        self.gear = gear;
+        self
+    }
+}
+
+impl Chunker for FastCdc2020 {
+    fn cut(&self, source: &[u8]) -> (u64, usize) {
+        fastcdc::cut(
+            source,
+            self.min_size,
+            self.avg_size,
+            self.max_size,
+            self.mask_s,
+            self.mask_l,
+            self.mask_s << 1,
+            self.mask_l << 1,
+            &self.gear,
+        )
+    }
+
+    fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+/// FastCDC as of 2016, which rolls the gear hash one byte at a time. Finds
+/// different cut points than the 2020 version, so repositories chunked with it
+/// keep deduplicating against themselves.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FastCdc2016 {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    mask_s: u64,
+    mask_l: u64,
+    gear: Gear,
+}
+
+impl FastCdc2016 {
+    pub fn new(min_size: u32, avg_size: u32, max_size: u32, level: Normalization) -> Self {
+        let (mask_s, mask_l) = masks(min_size, avg_size, max_size, level);
+        Self {
+            min_size: min_size as usize,
+            avg_size: avg_size as usize,
+            max_size: max_size as usize,
+            mask_s,
+            mask_l,
+            gear: Gear::CANONICAL,
+        }
+    }
+
+    /// Use the given gear table rather than the canonical one.
+    pub fn with_gear(mut self, gear: Gear) -> Self {
+        self.gear = gear;
+        self
+    }
+}
+
+impl Chunker for FastCdc2016 {
+    fn cut(&self, source: &[u8]) -> (u64, usize) {
+        let mut remaining = source.len();
+        if remaining <= self.min_size {
+            return (0, remaining);
+        }
+        let mut center = self.avg_size;
+        if remaining > self.max_size {
+            remaining = self.max_size;
+        } else if remaining < center {
+            center = remaining;
+        }
+
+        let table = self.gear.table();
+        let mut index = self.min_size;
+        let mut hash: u64 = 0;
+        while index < center {
+            hash = (hash << 1).wrapping_add(table[source[index] as usize]);
+            if (hash & self.mask_s) == 0 {
+                return (hash, index);
+            }
+            index += 1;
+        }
+        while index < remaining {
+            hash = (hash << 1).wrapping_add(table[source[index] as usize]);
+            if (hash & self.mask_l) == 0 {
+                return (hash, index);
+            }
+            index += 1;
+        }
+
+        (hash, remaining)
+    }
+
+    fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+/// Buzhash rolled over a window of the last 4095 bytes, as borg chunks with,
+/// with a table derived from a secret seed. Cuts where the low bits of the
+/// hash are zero, so chunk sizes are exponentially distributed between the
+/// minimum and maximum like with restic's Rabin fingerprints.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Buzhash {
+    min_size: usize,
+    max_size: usize,
+    window: usize,
+    mask: u32,
+    table: [u32; 256],
+}
+
+impl Buzhash {
+    pub fn new(min_size: u32, avg_size: u32, max_size: u32, seed: &[u8; 32]) -> Self {
+        masks(min_size, avg_size, max_size, Normalization::Level0);
+
+        let mut bytes = [0; 4 * 256];
+        blake3::Hasher::new_keyed(seed)
+            .update(b"buzhash table")
+            .finalize_xof()
+            .fill(&mut bytes);
+
+        Self {
+            min_size: min_size as usize,
+            max_size: max_size as usize,
+            window: BUZHASH_WINDOW.min(min_size as usize),
+            mask: (1 << fastcdc::logarithm2(avg_size)) - 1,
+            table: std::array::from_fn(|i| {
+                u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap())
+            }),
+        }
+    }
+}
+
+impl Chunker for Buzhash {
+    fn cut(&self, source: &[u8]) -> (u64, usize) {
+        if source.len() <= self.min_size {
+            return (0, source.len());
+        }
+        let remaining = source.len().min(self.max_size);
+
+        // The hash at each position is of the window of bytes before it, and
+        // the first position worth hashing is the minimum size.
+        let mut hash = source[self.min_size - self.window..self.min_size]
+            .iter()
+            .fold(0u32, |hash, &byte| {
+                hash.rotate_left(1) ^ self.table[byte as usize]
+            });
+
+        let mut index = self.min_size;
+        while index < remaining {
+            if (hash & self.mask) == 0 {
+                return (hash as u64, index);
+            }
+            let removed = self.table[source[index - self.window] as usize];
+            hash = hash.rotate_left(1)
+                ^ removed.rotate_left(self.window as u32)
+                ^ self.table[source[index] as usize];
+            index += 1;
+        }
+
+        (hash as u64, remaining)
+    }
+
+    fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+/// Chunks of one size, for block device and virtual machine images whose
+/// changes are aligned to blocks.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Fixed {
+    size: usize,
+}
+
+impl Fixed {
+    pub fn new(size: u32) -> Self {
+        assert!(size > 0);
+        Self {
+            size: size as usize,
+        }
+    }
+}
+
+impl Chunker for Fixed {
+    fn cut(&self, source: &[u8]) -> (u64, usize) {
+        (0, source.len().min(self.size))
+    }
+
+    fn max_size(&self) -> usize {
+        self.size
+    }
+}
+
+impl FromStr for ChunkerAlgorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "fastcdc-2020" => Ok(ChunkerAlgorithm::FastCdc2020),
+            "fastcdc-2016" => Ok(ChunkerAlgorithm::FastCdc2016),
+            "buzhash" => Ok(ChunkerAlgorithm::Buzhash),
+            "fixed" => Ok(ChunkerAlgorithm::Fixed),
+            _ => Err(format!(
+                "unknown chunker {:?}, expected fastcdc-2020, fastcdc-2016, buzhash or fixed",
+                name
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChunkerAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ChunkerAlgorithm::FastCdc2020 => "fastcdc-2020",
+            ChunkerAlgorithm::FastCdc2016 => "fastcdc-2016",
+            ChunkerAlgorithm::Buzhash => "buzhash",
+            ChunkerAlgorithm::Fixed => "fixed",
+        };
+
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits the whole source, returning the chunk lengths.
+    fn split(chunker: &dyn Chunker, source: &[u8]) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        let mut offset = 0;
+        while offset < source.len() {
+            let (_, length) = chunker.cut(&source[offset..]);
+            lengths.push(length);
+            offset += length;
+        }
+
+        lengths
+    }
+
+    #[test]
+    fn test_chunk_sizes_within_bounds() {
+        let mut source = vec![0; 4 * 1024 * 1024];
+        blake3::Hasher::new().finalize_xof().fill(&mut source);
+
+        let seed = [3; 32];
+        let chunkers: [Box<dyn Chunker>; 3] = [
+            Box::new(FastCdc2020::new(4096, 16384, 65536, Normalization::Level1)),
+            Box::new(FastCdc2016::new(4096, 16384, 65536, Normalization::Level1)),
+            Box::new(Buzhash::new(4096, 16384, 65536, &seed)),
+        ];
+
+        for chunker in &chunkers {
+            let lengths = split(chunker.as_ref(), &source);
+            let (last, rest) = lengths.split_last().unwrap();
+            assert!(rest.iter().all(|&length| (4096..=65536).contains(&length)));
+            assert!(*last <= 65536);
+
+            // Around the average, not stuck at either bound.
+            let average = source.len() / lengths.len();
+            assert!((8192..=32768).contains(&average), "average {}", average);
+        }
+
+        let lengths = split(&Fixed::new(1000 * 1000), &source);
+        assert_eq!(lengths[..4], [1000 * 1000; 4]);
+        assert_eq!(lengths[4], source.len() - 4 * 1000 * 1000);
+    }
+
+    #[test]
+    fn test_buzhash_cuts_are_content_defined() {
+        let mut source = vec![0; 1024 * 1024];
+        blake3::Hasher::new().finalize_xof().fill(&mut source);
+        let chunker = Buzhash::new(4096, 16384, 65536, &[3; 32]);
+        let lengths = split(&chunker, &source);
+
+        // Inserting bytes only moves the cut points around them.
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend_from_slice(&source);
+        let shifted_lengths = split(&chunker, &shifted);
+        assert_eq!(lengths[1..], shifted_lengths[1..]);
+
+        let other = split(&Buzhash::new(4096, 16384, 65536, &[4; 32]), &source);
+        assert_ne!(lengths, other);
+    }
+
+    #[test]
+    fn test_algorithm_names() {
+        for algorithm in [
+            ChunkerAlgorithm::FastCdc2020,
+            ChunkerAlgorithm::FastCdc2016,
+            ChunkerAlgorithm::Buzhash,
+            ChunkerAlgorithm::Fixed,
+        ] {
+            assert_eq!(algorithm.to_string().parse(), Ok(algorithm));
+        }
+    }
+}
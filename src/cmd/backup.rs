@@ -0,0 +1,1429 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{CStr, OsStr};
+use std::fs::{self, Metadata};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::process::{self, Stdio};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use flate2::read::GzDecoder;
+use log::debug;
+use walkdir::WalkDir;
+
+use crate::chunker::Chunker;
+use crate::fastcdc::StreamCDC;
+use crate::pack::{self, Compression, Packer};
+use crate::repo::{BlobClass, Dictionary, Hash, Index, Node, NodeKind, Repository, Snapshot, Tree};
+use crate::useg::{UPath, USeg};
+
+/// Number of packs written before the pending index is flushed to storage, so
+/// an interrupted backup leaves most of its work discoverable by the next run.
+const INDEX_FLUSH_PACKS: usize = 16;
+
+/// How a backup stores what it reads.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub compression: Compression,
+    /// Files up to this size are kept inline in their tree instead of in
+    /// blobs of their own, zero to never inline.
+    pub inline_size: u64,
+    /// Leaves out what changes without the files changing, atimes, which the
+    /// backup itself updates by reading files, ctimes, which change along
+    /// with them, inodes and link counts, which differ between copies, and
+    /// the time of files read from a stream, so backing up the same files
+    /// again, or an identical copy of them, gives the same root tree.
+    pub deterministic: bool,
+}
+
+/// State shared by every kind of backup: a packer per blob class, the index of
+/// packs not yet flushed and the set of blobs already stored.
+struct Session<'a> {
+    repository: &'a Repository,
+    packers: HashMap<BlobClass, Packer>,
+    index: Index,
+    known: HashSet<Hash>,
+    chunker: Rc<dyn Chunker>,
+    options: Options,
+    tree_dictionary: Option<(Hash, Dictionary)>,
+    data_dictionary: Option<(Hash, Dictionary)>,
+    /// Buffer files are chunked in, handed from one file to the next.
+    chunk_buffer: Vec<u8>,
+    /// Names of the users owning the files seen so far.
+    users: HashMap<u32, String>,
+}
+
+impl<'a> Session<'a> {
+    fn new(repository: &'a Repository, options: Options) -> Self {
+        let mut session = Self {
+            repository,
+            packers: BlobClass::ALL
+                .into_iter()
+                .map(|class| {
+                    let packer = Packer::new(
+                        *repository.pack_key(class),
+                        repository.cipher(),
+                        repository.config().pack_size,
+                    );
+                    (class, packer)
+                })
+                .collect(),
+            index: Index {
+                supersedes: Vec::new(),
+                packs: Vec::new(),
+            },
+            known: HashSet::new(),
+            chunker: Rc::from(pack::chunker(
+                &repository.config().chunker,
+                &repository.config().chunker_seed,
+            )),
+            options,
+            tree_dictionary: repository.current_dictionary(BlobClass::Tree),
+            data_dictionary: repository.current_dictionary(BlobClass::Data),
+            chunk_buffer: Vec::new(),
+            users: HashMap::new(),
+        };
+
+        let mut indexed = HashSet::new();
+
+        for existing in repository.indexes() {
+            for pack in existing.packs {
+                indexed.insert(pack.id);
+                session.known.extend(pack.blobs.iter().map(|blob| blob.id));
+            }
+        }
+
+        let recovered = repository.recover_packs(&indexed);
+        if !recovered.is_empty() {
+            debug!("recovered {} unindexed packs", recovered.len());
+
+            for pack in &recovered {
+                session.known.extend(pack.blobs.iter().map(|blob| blob.id));
+            }
+
+            session.index.packs = recovered;
+            session.flush_index();
+        }
+
+        session
+    }
+
+    /// Chunks a stream into data blobs, storing the ones not already known,
+    /// and returns the ids making up its content.
+    fn store_content(&mut self, reader: &mut dyn Read) -> Vec<Hash> {
+        let mut content = Vec::new();
+        let mut chunker = StreamCDC::with_chunker(reader, Rc::clone(&self.chunker))
+            .with_buffer(mem::take(&mut self.chunk_buffer));
+
+        while let Some(chunk) = chunker.next_chunk() {
+            let chunk = chunk.unwrap();
+            let id = self.repository.subkeys().blob_id(chunk.data);
+            content.push(id);
+
+            if self.known.insert(id) {
+                self.add_blob(id, BlobClass::Data, chunk.data);
+            }
+        }
+
+        self.chunk_buffer = chunker.into_buffer();
+        content
+    }
+
+    /// Stores a file of the given size, inline if it is small enough. The size
+    /// is only a hint, a file which has grown since is stored in blobs.
+    fn store_file(&mut self, reader: &mut dyn Read, size: u64) -> NodeKind {
+        let limit = self.options.inline_size;
+
+        if size == 0 || size > limit {
+            let content = self.store_content(reader);
+            return NodeKind::File {
+                content,
+                inline: None,
+            };
+        }
+
+        let mut data = Vec::new();
+        reader.take(limit + 1).read_to_end(&mut data).unwrap();
+
+        if data.len() as u64 > limit {
+            let content = self.store_content(&mut io::Cursor::new(data).chain(reader));
+            return NodeKind::File {
+                content,
+                inline: None,
+            };
+        }
+
+        NodeKind::File {
+            content: Vec::new(),
+            inline: Some(data).filter(|data| !data.is_empty()),
+        }
+    }
+
+    /// Name of the user with an id, empty if it has none.
+    fn user_name(&mut self, uid: u32) -> String {
+        self.users
+            .entry(uid)
+            .or_insert_with(|| user_name(uid))
+            .clone()
+    }
+
+    fn store_tree(&mut self, tree: &Tree) -> Hash {
+        let data = rmp_serde::to_vec(tree).unwrap();
+        let id = self.repository.subkeys().blob_id(&data);
+
+        if !self.known.insert(id) {
+            return id;
+        }
+
+        self.add_blob(id, BlobClass::Tree, &data);
+        id
+    }
+
+    /// Compresses and seals a blob into the packer of its class, finishing
+    /// packs as they fill.
+    fn add_blob(&mut self, id: Hash, class: BlobClass, data: &[u8]) {
+        let dictionary = match class {
+            BlobClass::Tree => &self.tree_dictionary,
+            BlobClass::Data => &self.data_dictionary,
+        };
+        let dictionary = dictionary
+            .as_ref()
+            .map(|(id, dictionary)| (*id, &dictionary.data[..]));
+
+        let compression = self.options.compression;
+        let packer = self.packers.get_mut(&class).unwrap();
+
+        if packer
+            .pack_blob(id, class, data, compression, dictionary)
+            .is_err()
+        {
+            finish_pack(self.repository, class, packer, &mut self.index);
+            packer
+                .pack_blob(id, class, data, compression, dictionary)
+                .unwrap();
+        }
+
+        if packer.should_pack() {
+            finish_pack(self.repository, class, packer, &mut self.index);
+        }
+    }
+
+    fn flush_index(&mut self) {
+        flush_index(self.repository, &mut self.index);
+    }
+
+    /// Writes out everything still buffered, leaving all stored blobs indexed.
+    fn flush(&mut self) {
+        for class in BlobClass::ALL {
+            let packer = self.packers.get_mut(&class).unwrap();
+            finish_pack(self.repository, class, packer, &mut self.index);
+        }
+        self.flush_index();
+    }
+
+    /// Flushes the session and records a snapshot of the given root tree.
+    fn commit(mut self, tree: Hash, root: Option<Node>, paths: Vec<UPath>) -> Hash {
+        self.flush();
+
+        let snapshot = Snapshot {
+            time: now(),
+            tree,
+            paths,
+            hostname: super::hostname(),
+            username: std::env::var("USER").unwrap_or_default(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            tags: Vec::new(),
+            original: None,
+            root,
+        };
+
+        let id = self.repository.write_snapshot(&snapshot);
+        debug!("wrote snapshot {}", id.to_hex());
+        id
+    }
+}
+
+pub fn run(repository: &Repository, path: &Path, options: Options) {
+    let mut session = Session::new(repository, options);
+    let mut trees = Vec::new();
+
+    for entry in WalkDir::new(path).sort_by_file_name() {
+        let entry = entry.unwrap();
+        let upath = UPath::from_path(entry.path());
+
+        debug!("entry path: {:?}", entry.path());
+
+        let metadata = entry.metadata().unwrap();
+
+        if entry.file_type().is_dir() {
+            let tree = Tree {
+                nodes: BTreeSet::new(),
+            };
+
+            trees.push((upath.clone(), tree, metadata.clone()));
+        }
+
+        if entry.file_type().is_file() {
+            let mut file = fs::OpenOptions::new()
+                .read(true)
+                .open(entry.path())
+                .unwrap();
+
+            let kind = session.store_file(&mut file, metadata.len());
+
+            add_node(&mut trees, kind, &upath, &metadata, &mut session);
+        }
+
+        if entry.file_type().is_symlink() {
+            let target = fs::read_link(entry.path()).unwrap();
+            let kind = NodeKind::Symlink {
+                link_target: UPath::from_path(&target),
+                links: if options.deterministic {
+                    1
+                } else {
+                    metadata.nlink()
+                },
+            };
+
+            add_node(&mut trees, kind, &upath, &metadata, &mut session);
+        }
+
+        let file_type = entry.file_type();
+        if file_type.is_char_device()
+            || file_type.is_block_device()
+            || file_type.is_fifo()
+            || file_type.is_socket()
+        {
+            let kind = NodeKind::Special {
+                rdev: metadata.rdev(),
+            };
+
+            add_node(&mut trees, kind, &upath, &metadata, &mut session);
+        }
+    }
+
+    let mut root = None;
+
+    // Trees were collected in pre-order, so popping them finishes every subtree
+    // before the directory containing it.
+    while let Some((upath, tree, metadata)) = trees.pop() {
+        let id = session.store_tree(&tree);
+        let kind = NodeKind::Dir { subtree: id };
+
+        if trees.is_empty() {
+            root = Some((id, new_node(kind, b"", &metadata, &mut session)));
+        } else {
+            add_node(&mut trees, kind, &upath, &metadata, &mut session);
+        }
+    }
+
+    let (tree, root) = root.expect("backup path is not a directory");
+    let paths = vec![UPath::from_path(&path.canonicalize().unwrap())];
+    session.commit(tree, Some(root), paths);
+}
+
+/// Backs up a stream as a snapshot containing a single file.
+pub fn run_stdin(repository: &Repository, filename: &str, reader: &mut dyn Read, options: Options) {
+    let mut session = Session::new(repository, options);
+    let content = session.store_content(reader);
+    let root = session.store_tree(&single_file_tree(filename, content, options));
+    session.commit(root, None, vec![UPath::from_path(Path::new(filename))]);
+}
+
+/// Backs up the output of a command as a snapshot containing a single file. No
+/// snapshot is recorded if the command fails, though the data read so far is
+/// kept for deduplication.
+pub fn run_command(repository: &Repository, filename: &str, command: &[String], options: Options) {
+    let (program, args) = command.split_first().expect("no command given");
+    let mut child = process::Command::new(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|err| panic!("failed to run {:?}: {}", program, err));
+
+    let mut session = Session::new(repository, options);
+    let content = session.store_content(&mut child.stdout.take().unwrap());
+    let status = child.wait().unwrap();
+
+    if !status.success() {
+        session.flush();
+        panic!(
+            "{:?} failed with {}, no snapshot was created",
+            program, status
+        );
+    }
+
+    let root = session.store_tree(&single_file_tree(filename, content, options));
+    session.commit(root, None, vec![UPath::from_path(Path::new(filename))]);
+}
+
+/// Directory being assembled from the entries of an archive, which may list
+/// children before their parents or leave parents out entirely.
+#[derive(Default)]
+struct PendingDir {
+    node: Option<Node>,
+    children: BTreeMap<Vec<u8>, Pending>,
+}
+
+enum Pending {
+    Dir(PendingDir),
+    Leaf(Node),
+}
+
+impl PendingDir {
+    fn insert(&mut self, segments: &[&[u8]], node: Node) {
+        let (name, rest) = segments.split_first().unwrap();
+
+        if rest.is_empty() {
+            match (&node.kind, self.children.get_mut(*name)) {
+                (NodeKind::Dir { .. }, Some(Pending::Dir(dir))) => dir.node = Some(node),
+                (NodeKind::Dir { .. }, _) => {
+                    let dir = PendingDir {
+                        node: Some(node),
+                        children: BTreeMap::new(),
+                    };
+
+                    self.children.insert(name.to_vec(), Pending::Dir(dir));
+                }
+                (_, _) => {
+                    self.children.insert(name.to_vec(), Pending::Leaf(node));
+                }
+            }
+
+            return;
+        }
+
+        let child = self
+            .children
+            .entry(name.to_vec())
+            .or_insert_with(|| Pending::Dir(PendingDir::default()));
+
+        if let Pending::Leaf(_) = child {
+            *child = Pending::Dir(PendingDir::default());
+        }
+
+        let Pending::Dir(dir) = child else {
+            unreachable!();
+        };
+
+        dir.insert(rest, node);
+    }
+
+    /// Stores this directory and everything below it, returning its tree id.
+    fn store(self, session: &mut Session) -> Hash {
+        let mut tree = Tree {
+            nodes: BTreeSet::new(),
+        };
+
+        for (name, child) in self.children {
+            let node = match child {
+                Pending::Leaf(node) => node,
+                Pending::Dir(dir) => {
+                    let mut node = dir.node.clone().unwrap_or_else(|| implicit_dir(&name));
+                    node.kind = NodeKind::Dir {
+                        subtree: dir.store(session),
+                    };
+                    node
+                }
+            };
+
+            tree.nodes.insert(node);
+        }
+
+        session.store_tree(&tree)
+    }
+}
+
+/// Imports a tar archive, optionally gzip or zstd compressed, as a snapshot
+/// without extracting it to disk.
+pub fn run_tar(repository: &Repository, path: &Path, options: Options) {
+    let file = fs::File::open(path).unwrap();
+    let name = path.file_name().unwrap().to_string_lossy();
+    let reader: Box<dyn Read> = if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Box::new(GzDecoder::new(file))
+    } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+        Box::new(zstd::Decoder::new(file).unwrap())
+    } else {
+        Box::new(file)
+    };
+
+    let mut session = Session::new(repository, options);
+    let mut root = PendingDir::default();
+    let mut contents = HashMap::new();
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let entry_path = entry.path_bytes().into_owned();
+        debug!("tar entry: {:?}", String::from_utf8_lossy(&entry_path));
+
+        let Some(segments) = archive_segments(&entry_path) else {
+            log::warn!(
+                "skipping tar entry outside the archive {:?}",
+                String::from_utf8_lossy(&entry_path)
+            );
+            continue;
+        };
+
+        let pax = PaxOverrides::read(&mut entry);
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let (file_type, kind) = match entry_type {
+            tar::EntryType::Directory => {
+                let kind = NodeKind::Dir {
+                    subtree: Hash { bytes: [0; 32] },
+                };
+
+                (libc::S_IFDIR, kind)
+            }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let size = entry.header().size().unwrap();
+                let kind = session.store_file(&mut entry, size);
+                contents.insert(segments.join(&b'/'), kind.clone());
+                (libc::S_IFREG, kind)
+            }
+            tar::EntryType::Symlink => {
+                let target = entry.link_name_bytes().unwrap().into_owned();
+                if archive_segments(&target).is_none() {
+                    log::warn!(
+                        "skipping symlink {:?} pointing outside the archive",
+                        String::from_utf8_lossy(&entry_path)
+                    );
+                    continue;
+                }
+
+                let kind = NodeKind::Symlink {
+                    link_target: UPath::from_path(Path::new(OsStr::from_bytes(&target))),
+                    links: 1,
+                };
+
+                (libc::S_IFLNK, kind)
+            }
+            tar::EntryType::Link => {
+                // Hard links always refer to an earlier entry in the archive,
+                // so the link becomes a file sharing the content of its target.
+                let target = entry.link_name_bytes().unwrap().into_owned();
+                let Some(target) = archive_segments(&target) else {
+                    log::warn!(
+                        "skipping hard link {:?} pointing outside the archive",
+                        String::from_utf8_lossy(&entry_path)
+                    );
+                    continue;
+                };
+
+                // Archives made with excludes can link to a file left out.
+                let Some(kind) = contents.get(&target.join(&b'/')) else {
+                    log::warn!(
+                        "skipping hard link {:?} to a file missing from the archive",
+                        String::from_utf8_lossy(&entry_path)
+                    );
+                    continue;
+                };
+
+                (libc::S_IFREG, kind.clone())
+            }
+            tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                // Archivers leave the device fields of fifos blank.
+                let major = header.device_major().ok().flatten().unwrap_or(0);
+                let minor = header.device_minor().ok().flatten().unwrap_or(0);
+                let file_type = match entry_type {
+                    tar::EntryType::Char => libc::S_IFCHR,
+                    tar::EntryType::Block => libc::S_IFBLK,
+                    _ => libc::S_IFIFO,
+                };
+                let kind = NodeKind::Special {
+                    rdev: libc::makedev(major, minor),
+                };
+
+                (file_type, kind)
+            }
+            _ => {
+                log::warn!("skipping unsupported tar entry {:?}", entry_type);
+                continue;
+            }
+        };
+
+        if segments.is_empty() && file_type != libc::S_IFDIR {
+            log::warn!("skipping {:?} entry for the archive root", entry_type);
+            continue;
+        }
+
+        let header = entry.header();
+        let mtime = pax.mtime.unwrap_or_else(|| header.mtime().unwrap() as i64);
+        let node = Node {
+            name: USeg::from_segment_bytes(segments.last().copied().unwrap_or_default()),
+            mode: file_type | header.mode().unwrap() & 0o7777,
+            mtime,
+            atime: mtime,
+            ctime: mtime,
+            uid: pax.uid.unwrap_or_else(|| header.uid().unwrap() as u32),
+            gid: pax.gid.unwrap_or_else(|| header.gid().unwrap() as u32),
+            user: header
+                .username_bytes()
+                .map(|user| String::from_utf8_lossy(user).into_owned())
+                .unwrap_or_default(),
+            inode: 0,
+            kind,
+        };
+
+        // The entry for the archive itself, usually `./`.
+        if segments.is_empty() {
+            root.node = Some(node);
+        } else {
+            root.insert(&segments, node);
+        }
+    }
+
+    let node = root.node.take();
+    let tree = root.store(&mut session);
+    let node = node.map(|node| Node {
+        kind: NodeKind::Dir { subtree: tree },
+        ..node
+    });
+
+    let paths = vec![UPath::from_path(&path.canonicalize().unwrap())];
+    session.commit(tree, node, paths);
+}
+
+/// Metadata from the PAX extended header of a tar entry, which overrides the
+/// entry's header for values too large for it or times with fractions.
+#[derive(Default)]
+struct PaxOverrides {
+    mtime: Option<i64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl PaxOverrides {
+    fn read<R: Read>(entry: &mut tar::Entry<'_, R>) -> Self {
+        let mut overrides = Self::default();
+        let Ok(Some(extensions)) = entry.pax_extensions() else {
+            return overrides;
+        };
+
+        for extension in extensions.flatten() {
+            let (Ok(key), Ok(value)) = (extension.key(), extension.value()) else {
+                continue;
+            };
+
+            match key {
+                "mtime" => overrides.mtime = parse_pax_time(value),
+                "uid" => overrides.uid = value.parse().ok(),
+                "gid" => overrides.gid = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        overrides
+    }
+}
+
+/// Parses a PAX time such as `1700000000.25`, rounding down to whole seconds.
+fn parse_pax_time(value: &str) -> Option<i64> {
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let seconds = seconds.parse::<i64>().ok()?;
+    if value.starts_with('-') && fraction.bytes().any(|digit| digit != b'0') {
+        return seconds.checked_sub(1);
+    }
+
+    Some(seconds)
+}
+
+/// Splits a path from an archive into its segments, leaving out empty and `.`
+/// ones, or returns `None` if it is absolute or has a `..` segment and so could
+/// point outside the archive.
+fn archive_segments(path: &[u8]) -> Option<Vec<&[u8]>> {
+    if path.starts_with(b"/") {
+        return None;
+    }
+
+    let segments = path
+        .split(|&byte| byte == b'/')
+        .filter(|segment| !segment.is_empty() && *segment != b".")
+        .collect::<Vec<_>>();
+
+    if segments.iter().any(|segment| *segment == b"..") {
+        return None;
+    }
+
+    Some(segments)
+}
+
+/// Metadata for a directory an archive never listed itself.
+fn implicit_dir(name: &[u8]) -> Node {
+    Node {
+        name: USeg::from_segment_bytes(name),
+        mode: libc::S_IFDIR | 0o755,
+        mtime: 0,
+        atime: 0,
+        ctime: 0,
+        uid: 0,
+        gid: 0,
+        user: String::new(),
+        inode: 0,
+        kind: NodeKind::Dir {
+            subtree: Hash { bytes: [0; 32] },
+        },
+    }
+}
+
+fn single_file_tree(filename: &str, content: Vec<Hash>, options: Options) -> Tree {
+    let time = if options.deterministic { 0 } else { now() };
+    let node = Node {
+        name: USeg::from_segment_bytes(filename.as_bytes()),
+        mode: libc::S_IFREG | 0o644,
+        mtime: time,
+        atime: time,
+        ctime: time,
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        user: std::env::var("USER").unwrap_or_default(),
+        inode: 0,
+        kind: NodeKind::File {
+            content,
+            inline: None,
+        },
+    };
+
+    Tree {
+        nodes: BTreeSet::from([node]),
+    }
+}
+
+/// Looks up the name of a user in the password database.
+fn user_name(uid: u32) -> String {
+    let mut buffer = vec![0; 1024];
+
+    loop {
+        let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+        let mut result = std::ptr::null_mut();
+        let error = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+
+        if error == libc::ERANGE {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+
+        if error != 0 || result.is_null() {
+            return String::new();
+        }
+
+        let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+        return name.to_string_lossy().into_owned();
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+fn add_node(
+    trees: &mut [(UPath, Tree, Metadata)],
+    kind: NodeKind,
+    upath: &UPath,
+    metadata: &Metadata,
+    session: &mut Session,
+) {
+    let node = new_node(kind, upath.last_segment(), metadata, session);
+
+    debug!("node: {:?}", node);
+
+    let parent = upath.parent().unwrap();
+    let tree_idx = trees
+        .iter()
+        .position(|(path, _, _)| path == &parent)
+        .unwrap();
+    trees[tree_idx].1.nodes.insert(node);
+}
+
+fn new_node(kind: NodeKind, name: &[u8], metadata: &Metadata, session: &mut Session) -> Node {
+    let options = session.options;
+    Node {
+        name: USeg::from_segment_bytes(name),
+        mode: metadata.mode(),
+        mtime: metadata.mtime(),
+        atime: if options.deterministic {
+            0
+        } else {
+            metadata.atime()
+        },
+        ctime: if options.deterministic {
+            0
+        } else {
+            metadata.ctime()
+        },
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        user: session.user_name(metadata.uid()),
+        inode: if options.deterministic {
+            0
+        } else {
+            metadata.ino()
+        },
+        kind,
+    }
+}
+
+fn finish_pack(repository: &Repository, class: BlobClass, packer: &mut Packer, index: &mut Index) {
+    if packer.is_empty() {
+        return;
+    }
+
+    let (blobs, overhead) = (packer.len(), packer.overhead());
+    let (pack, data) = packer.finish();
+
+    debug!(
+        "writing {:?} pack {} with {} blobs, {} bytes of overhead",
+        class,
+        pack.id.to_hex(),
+        blobs,
+        overhead
+    );
+
+    repository.write_pack(class, pack.id, &data);
+    index.packs.push(pack);
+
+    if index.packs.len() >= INDEX_FLUSH_PACKS {
+        flush_index(repository, index);
+    }
+}
+
+fn flush_index(repository: &Repository, index: &mut Index) {
+    if index.packs.is_empty() {
+        return;
+    }
+
+    let id = repository.write_index(index);
+    debug!(
+        "wrote index {} with {} packs",
+        id.to_hex(),
+        index.packs.len()
+    );
+    index.packs.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::repo::{Backend, ChunkerParams, Cipher, Key, LocalBackend, ObjectClass, PackSize};
+
+    /// Records the class and path of every object read through it.
+    struct Recording(LocalBackend, Rc<RefCell<Vec<(ObjectClass, String)>>>);
+
+    impl Backend for Recording {
+        fn exists(&self, class: ObjectClass, path: &str) -> bool {
+            self.0.exists(class, path)
+        }
+
+        fn read(&self, class: ObjectClass, path: &str) -> io::Result<Vec<u8>> {
+            self.1.borrow_mut().push((class, path.to_string()));
+            self.0.read(class, path)
+        }
+
+        fn read_range(
+            &self,
+            class: ObjectClass,
+            path: &str,
+            offset: u64,
+            length: usize,
+        ) -> io::Result<Vec<u8>> {
+            self.1.borrow_mut().push((class, path.to_string()));
+            self.0.read_range(class, path, offset, length)
+        }
+
+        fn size(&self, class: ObjectClass, path: &str) -> io::Result<u64> {
+            self.0.size(class, path)
+        }
+
+        fn write(&self, class: ObjectClass, path: &str, data: &[u8]) {
+            self.0.write(class, path, data)
+        }
+
+        fn list(&self, class: ObjectClass, dir: &str) -> Vec<String> {
+            self.0.list(class, dir)
+        }
+
+        fn remove(&self, class: ObjectClass, path: &str) {
+            self.0.remove(class, path)
+        }
+    }
+
+    fn packs(root: &Path) -> BTreeSet<PathBuf> {
+        WalkDir::new(root)
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "pack"))
+            .collect()
+    }
+
+    /// Backs up the source twice, touching the atime of every file in between
+    /// as reading them might, and returns the root trees of both snapshots.
+    /// Touching a file also bumps its ctime, so it waits for the next second
+    /// first for the ctimes to differ.
+    /// Fills a directory with the same files every time, down to their
+    /// mtimes.
+    fn populate(source: &Path) {
+        fs::create_dir_all(source.join("sub/deeper")).unwrap();
+        fs::write(source.join("sub/deeper/big"), vec![7; 300_000]).unwrap();
+        fs::write(source.join("sub/small"), b"inline me").unwrap();
+        fs::write(source.join("top"), b"top level file").unwrap();
+        std::os::unix::fs::symlink("sub/small", source.join("link")).unwrap();
+
+        let times = [libc::timespec {
+            tv_sec: 1_000_000,
+            tv_nsec: 0,
+        }; 2];
+        for entry in WalkDir::new(source) {
+            let path =
+                std::ffi::CString::new(entry.unwrap().path().as_os_str().as_bytes()).unwrap();
+            let ret = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            assert_eq!(ret, 0);
+        }
+    }
+
+    #[test]
+    fn test_deterministic_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = |deterministic, name: &str| {
+            let root = dir.path().join(name);
+            let repository = Repository::init(
+                &root.join("repo"),
+                Key::generate(),
+                Cipher::default(),
+                ChunkerParams::default(),
+                PackSize::default(),
+                None,
+            );
+            let options = Options {
+                compression: Compression::Auto,
+                inline_size: 128,
+                deterministic,
+            };
+
+            // Two copies made separately, with inodes and ctimes of their own.
+            for copy in ["a", "b"] {
+                populate(&root.join(copy));
+                run(&repository, &root.join(copy), options);
+            }
+
+            let stdin = b"streamed".repeat(1000);
+            for _ in 0..2 {
+                run_stdin(&repository, "stream", &mut &stdin[..], options);
+            }
+
+            let mut roots = repository
+                .snapshots()
+                .into_iter()
+                .map(|(_, snapshot)| snapshot.tree)
+                .collect::<Vec<_>>();
+            roots.sort();
+            roots.dedup();
+            roots.len()
+        };
+
+        // One directory root and one stream root.
+        assert_eq!(roots(true, "deterministic"), 2);
+        assert!(roots(false, "volatile") > 2);
+    }
+
+    #[test]
+    fn test_resume_reuses_orphaned_packs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, root) = (dir.path().join("source"), dir.path().join("repo"));
+        fs::create_dir_all(source.join("sub")).unwrap();
+        let mut big = vec![0; 3_000_000];
+        blake3::Hasher::new().finalize_xof().fill(&mut big);
+        fs::write(source.join("sub/big"), &big).unwrap();
+        fs::write(source.join("small"), b"small file").unwrap();
+
+        let key = Key::generate();
+        Repository::init(
+            &root,
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            Some(PathBuf::from("cold")),
+        );
+        let options = Options {
+            compression: Compression::Auto,
+            inline_size: 0,
+            deterministic: true,
+        };
+        run(&Repository::open(&root, key), &source, options);
+
+        // Killed after writing its packs but before flushing an index.
+        fs::remove_dir_all(root.join("index")).unwrap();
+        fs::remove_dir_all(root.join("snapshot")).unwrap();
+        let orphaned = packs(&root);
+        assert!(orphaned.len() >= 2);
+
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let open = || {
+            let backend = Recording(
+                LocalBackend::new(&root, &root.join("cold")),
+                Rc::clone(&reads),
+            );
+            Repository::with_backend(Box::new(backend), key)
+        };
+
+        // Tree packs are read back, while cold data packs are recovered from
+        // their hot header copies.
+        run(&open(), &source, options);
+        assert_eq!(packs(&root), orphaned);
+        assert!(
+            reads
+                .borrow()
+                .iter()
+                .any(|(_, path)| path.ends_with(".pack"))
+        );
+        assert!(
+            !reads
+                .borrow()
+                .iter()
+                .any(|(class, _)| *class == ObjectClass::Cold)
+        );
+
+        // Once indexed, packs aren't read again to find out what they hold.
+        reads.borrow_mut().clear();
+        let repository = open();
+        run(&repository, &source, options);
+        assert_eq!(packs(&root), orphaned);
+        assert!(
+            !reads
+                .borrow()
+                .iter()
+                .any(|(_, path)| path.ends_with(".pack"))
+        );
+
+        let blobs = repository
+            .indexes()
+            .into_iter()
+            .flat_map(|index| index.packs)
+            .flat_map(|pack| pack.blobs)
+            .collect::<Vec<_>>();
+        let size = blobs
+            .iter()
+            .filter(|blob| blob.kind.class() == BlobClass::Data)
+            .map(|blob| repository.read_blob(&blob.id).len())
+            .sum::<usize>();
+        assert_eq!(size, big.len() + b"small file".len());
+    }
+
+    #[test]
+    fn test_resume_drops_abandoned_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, root) = (dir.path().join("source"), dir.path().join("repo"));
+        fs::create_dir_all(&source).unwrap();
+        let mut big = vec![0; 3_000_000];
+        blake3::Hasher::new().finalize_xof().fill(&mut big);
+        fs::write(source.join("big"), &big).unwrap();
+
+        let key = Key::generate();
+        Repository::init(
+            &root,
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            Some(PathBuf::from("cold")),
+        );
+        let options = Options {
+            compression: Compression::Auto,
+            inline_size: 0,
+            deterministic: true,
+        };
+        run(&Repository::open(&root, key), &source, options);
+
+        // Killed after copying the headers of the data packs but before
+        // uploading them.
+        fs::remove_dir_all(root.join("index")).unwrap();
+        fs::remove_dir_all(root.join("snapshot")).unwrap();
+        fs::remove_dir_all(root.join("cold")).unwrap();
+        let abandoned = fs::read_dir(root.join("header"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert!(!abandoned.is_empty());
+
+        run(&Repository::open(&root, key), &source, options);
+        assert!(abandoned.iter().all(|path| !path.exists()));
+
+        let repository = Repository::open(&root, key);
+        let (_, snapshot) = repository.snapshots().pop().unwrap();
+        let node = crate::cmd::lookup(&repository, snapshot.tree, "big");
+        let mut data = Vec::new();
+        let NodeKind::File { content, inline } = &node.kind else {
+            panic!("not a file");
+        };
+        repository
+            .content_reader(content, inline.as_deref())
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, big);
+    }
+
+    #[test]
+    fn test_owner_names() {
+        assert_eq!(user_name(0), "root");
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("file"), b"owned").unwrap();
+        let fifo = std::ffi::CString::new(source.join("pipe").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        let repository = Repository::init(
+            &dir.path().join("repo"),
+            Key::generate(),
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = Options {
+            compression: Compression::Auto,
+            inline_size: 128,
+            deterministic: false,
+        };
+        run(&repository, &source, options);
+
+        let (_, snapshot) = repository.snapshots().pop().unwrap();
+        let mut nodes = repository.read_tree(&snapshot.tree).nodes;
+        let node = nodes.pop_first().unwrap();
+        assert_eq!(node.user, user_name(unsafe { libc::getuid() }));
+        let pipe = nodes.pop_first().unwrap();
+        assert_eq!(pipe.mode, libc::S_IFIFO | 0o600);
+        assert!(matches!(pipe.kind, NodeKind::Special { .. }));
+
+        let root = snapshot.root.unwrap();
+        assert_eq!(root.mode & libc::S_IFMT, libc::S_IFDIR);
+        assert!(matches!(root.kind, NodeKind::Dir { subtree } if subtree == snapshot.tree));
+    }
+
+    /// Reads back the single file of the newest snapshot with that name.
+    fn streamed_file(repository: &Repository, filename: &str) -> Vec<u8> {
+        let (_, snapshot) = repository
+            .snapshots()
+            .into_iter()
+            .rfind(|(_, snapshot)| snapshot.paths == [UPath::from_path(Path::new(filename))])
+            .unwrap();
+        let node = crate::cmd::lookup(repository, snapshot.tree, filename);
+        let NodeKind::File { content, inline } = node.kind else {
+            panic!("not a file");
+        };
+        assert!(inline.is_none());
+
+        let mut data = Vec::new();
+        repository
+            .content_reader(&content, None)
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Key::generate();
+        let root = dir.path().join("repo");
+        Repository::init(
+            &root,
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = Options {
+            compression: Compression::Auto,
+            inline_size: 128,
+            deterministic: false,
+        };
+
+        let repository = Repository::open(&root, key);
+        let mut stdin = vec![0; 2_000_000];
+        blake3::Hasher::new().finalize_xof().fill(&mut stdin);
+        run_stdin(&repository, "dump.sql", &mut &stdin[..], options);
+
+        let command = ["sh", "-c", "printf 'from a command'"].map(String::from);
+        run_command(&repository, "command.txt", &command, options);
+
+        let repository = Repository::open(&root, key);
+        assert_eq!(streamed_file(&repository, "dump.sql"), stdin);
+        assert_eq!(streamed_file(&repository, "command.txt"), b"from a command");
+
+        // A failing command leaves no snapshot behind.
+        let failing = ["sh", "-c", "printf partial; exit 3"].map(String::from);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            run_command(&repository, "failed.txt", &failing, options);
+        }));
+        assert!(result.is_err());
+        assert_eq!(Repository::open(&root, key).snapshots().len(), 2);
+    }
+
+    #[test]
+    fn test_tar_import() {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::fast(),
+        ));
+        let mut append = |path: &str, entry_type, mode, data: &[u8], link: Option<&str>| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(mode);
+            header.set_mtime(12345);
+            header.set_uid(1000);
+            header.set_gid(1000);
+            header.set_username("alice").unwrap();
+            header.set_size(data.len() as u64);
+            if entry_type == tar::EntryType::Char {
+                header.set_device_major(1).unwrap();
+                header.set_device_minor(3).unwrap();
+            }
+            match link {
+                Some(target) => builder.append_link(&mut header, path, target).unwrap(),
+                None => builder.append_data(&mut header, path, data).unwrap(),
+            }
+        };
+
+        append("./", tar::EntryType::Directory, 0o750, b"", None);
+        append("./dir/", tar::EntryType::Directory, 0o700, b"", None);
+        append(
+            "./dir/file",
+            tar::EntryType::Regular,
+            0o644,
+            b"contents",
+            None,
+        );
+        append(
+            "./dir/hard",
+            tar::EntryType::Link,
+            0o644,
+            b"",
+            Some("dir/file"),
+        );
+        append(
+            "./dir/soft",
+            tar::EntryType::Symlink,
+            0o777,
+            b"",
+            Some("file"),
+        );
+        append("./dir/null", tar::EntryType::Char, 0o666, b"", None);
+        append("./dir/pipe", tar::EntryType::Fifo, 0o600, b"", None);
+        append(
+            "./implicit/deep/x",
+            tar::EntryType::Regular,
+            0o644,
+            b"x",
+            None,
+        );
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.tar.gz");
+        fs::write(&path, archive).unwrap();
+
+        let repository = Repository::init(
+            &dir.path().join("repo"),
+            Key::generate(),
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = Options {
+            compression: Compression::Auto,
+            inline_size: 128,
+            deterministic: false,
+        };
+        run_tar(&repository, &path, options);
+
+        // The archive's own entry becomes the metadata of the snapshot root.
+        let (_, snapshot) = repository.snapshots().pop().unwrap();
+        let root = snapshot.root.unwrap();
+        assert_eq!(root.mode, libc::S_IFDIR | 0o750);
+        assert_eq!(
+            (root.mtime, root.uid, root.user.as_str()),
+            (12345, 1000, "alice")
+        );
+
+        let lookup = |path| crate::cmd::lookup(&repository, snapshot.tree, path);
+        assert_eq!(lookup("dir").mode, libc::S_IFDIR | 0o700);
+        let NodeKind::File { inline, .. } = lookup("dir/file").kind else {
+            panic!("not a file");
+        };
+        assert_eq!(inline.as_deref(), Some(&b"contents"[..]));
+        let NodeKind::File { inline, .. } = lookup("dir/hard").kind else {
+            panic!("not a file");
+        };
+        assert_eq!(inline.as_deref(), Some(&b"contents"[..]));
+        let NodeKind::Symlink { link_target, .. } = lookup("dir/soft").kind else {
+            panic!("not a symlink");
+        };
+        assert_eq!(link_target.to_bytes(), b"file");
+
+        // Device nodes and fifos are kept rather than dropped.
+        let null = lookup("dir/null");
+        assert_eq!(null.mode, libc::S_IFCHR | 0o666);
+        assert!(matches!(null.kind, NodeKind::Special { rdev } if rdev == libc::makedev(1, 3)));
+        let pipe = lookup("dir/pipe");
+        assert_eq!(pipe.mode, libc::S_IFIFO | 0o600);
+        assert!(matches!(pipe.kind, NodeKind::Special { .. }));
+
+        // Directories the archive never listed are made up.
+        assert_eq!(lookup("implicit/deep").mode, libc::S_IFDIR | 0o755);
+        assert_eq!(
+            crate::cmd::node_size(&repository, &lookup("implicit/deep/x")),
+            1
+        );
+    }
+
+    #[test]
+    fn test_tar_import_outside_archive() {
+        let mut builder = tar::Builder::new(Vec::new());
+        // The tar crate refuses to write such names, so they are set raw.
+        let mut append = |path: &[u8], entry_type, link: &[u8], data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_size(data.len() as u64);
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..path.len()].copy_from_slice(path);
+            gnu.linkname[..link.len()].copy_from_slice(link);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        };
+
+        append(b"./ok", tar::EntryType::Regular, b"", b"ok");
+        append(b"a/../../etc/x", tar::EntryType::Regular, b"", b"x");
+        append(b"/etc/y", tar::EntryType::Regular, b"", b"y");
+        append(b"./up", tar::EntryType::Symlink, b"../../etc", b"");
+        append(b"./root", tar::EntryType::Symlink, b"/etc", b"");
+        append(b"./hard", tar::EntryType::Link, b"../ok", b"");
+        append(b"./fine", tar::EntryType::Symlink, b"ok", b"");
+        let archive = builder.into_inner().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.tar");
+        fs::write(&path, archive).unwrap();
+
+        let repository = Repository::init(
+            &dir.path().join("repo"),
+            Key::generate(),
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = Options {
+            compression: Compression::Auto,
+            inline_size: 128,
+            deterministic: false,
+        };
+        run_tar(&repository, &path, options);
+
+        let (_, snapshot) = repository.snapshots().pop().unwrap();
+        let names = repository
+            .read_tree(&snapshot.tree)
+            .nodes
+            .into_iter()
+            .map(|node| node.name.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [&b"fine"[..], b"ok"]);
+    }
+
+    #[test]
+    fn test_tar_import_pax_and_missing_links() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_mtime(1);
+        header.set_uid(1);
+        header.set_gid(1);
+        header.set_size(4);
+        builder
+            .append_pax_extensions([
+                ("mtime", &b"17179869184.75"[..]),
+                ("uid", b"4294967294"),
+                ("gid", b"3000000000"),
+            ])
+            .unwrap();
+        builder
+            .append_data(&mut header, "big", &b"data"[..])
+            .unwrap();
+
+        // A link to a file left out of the archive, as `tar --exclude` makes.
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_mode(0o644);
+        header.set_mtime(1);
+        header.set_uid(1);
+        header.set_gid(1);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "orphan", "excluded")
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.tar");
+        fs::write(&path, archive).unwrap();
+
+        let repository = Repository::init(
+            &dir.path().join("repo"),
+            Key::generate(),
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = Options {
+            compression: Compression::Auto,
+            inline_size: 128,
+            deterministic: false,
+        };
+        run_tar(&repository, &path, options);
+
+        let (_, snapshot) = repository.snapshots().pop().unwrap();
+        let nodes = repository.read_tree(&snapshot.tree).nodes;
+        assert_eq!(nodes.len(), 1);
+        let node = nodes.first().unwrap();
+        assert_eq!(
+            (node.mtime, node.uid, node.gid),
+            (17179869184, 4294967294, 3000000000)
+        );
+        assert_eq!(parse_pax_time("-1.5"), Some(-2));
+    }
+}
@@ -0,0 +1,167 @@
+use std::io::{self, Write};
+
+use clap::Subcommand;
+use serde::Serialize;
+
+use crate::repo::{Hash, Repository};
+
+#[derive(Subcommand, Debug)]
+pub enum Object {
+    /// the repository config
+    Config,
+    /// an index, by id prefix
+    Index { id: String },
+    /// a snapshot, by id prefix or `latest`
+    Snapshot { id: String },
+    /// a tree blob, by full id
+    Tree { id: String },
+    /// the raw contents of a data blob, by full id
+    Blob { id: String },
+    /// the header of a pack, by id prefix
+    Pack { id: String },
+}
+
+pub fn run(repository: &Repository, object: Object) {
+    write(repository, object, &mut io::stdout().lock());
+}
+
+fn write(repository: &Repository, object: Object, out: &mut dyn Write) {
+    match object {
+        Object::Config => write_json(out, repository.config()),
+        Object::Index { id } => write_json(out, &repository.find_index(&id).1),
+        Object::Snapshot { id } => write_json(out, &repository.find_snapshot(&id).1),
+        Object::Tree { id } => write_json(out, &repository.read_tree(&parse_id(&id))),
+        Object::Blob { id } => {
+            let data = repository.read_blob(&parse_id(&id));
+            out.write_all(&data).unwrap();
+        }
+        Object::Pack { id } => write_json(out, &repository.find_pack(&id).1),
+    }
+}
+
+fn parse_id(id: &str) -> Hash {
+    Hash::from_hex(id).unwrap_or_else(|| panic!("invalid id {:?}", id))
+}
+
+fn write_json<T: Serialize>(out: &mut dyn Write, value: &T) {
+    serde_json::to_writer_pretty(&mut *out, value).unwrap();
+    writeln!(out).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::cmd::backup;
+    use crate::pack::Compression;
+    use crate::repo::{ChunkerParams, Cipher, Key, NodeKind, PackSize};
+
+    fn cat(repository: &Repository, object: Object) -> Vec<u8> {
+        let mut out = Vec::new();
+        write(repository, object, &mut out);
+        out
+    }
+
+    fn cat_json(repository: &Repository, object: Object) -> Value {
+        serde_json::from_slice(&cat(repository, object)).unwrap()
+    }
+
+    #[test]
+    fn test_cat() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("file"), b"some file contents").unwrap();
+
+        let repository = Repository::init(
+            &dir.path().join("repo"),
+            Key::generate(),
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = backup::Options {
+            compression: Compression::Auto,
+            inline_size: 0,
+            deterministic: false,
+        };
+        backup::run(&repository, &source, options);
+
+        let config = cat_json(&repository, Object::Config);
+        assert_eq!(config, serde_json::to_value(repository.config()).unwrap());
+
+        let (snapshot_id, snapshot) = repository.snapshots().pop().unwrap();
+        let latest = cat_json(
+            &repository,
+            Object::Snapshot {
+                id: "latest".to_string(),
+            },
+        );
+        let by_prefix = cat_json(
+            &repository,
+            Object::Snapshot {
+                id: snapshot_id.to_hex()[..8].to_string(),
+            },
+        );
+        assert_eq!(latest, by_prefix);
+        assert_eq!(latest["tree"], snapshot.tree.to_hex());
+
+        let tree = cat_json(
+            &repository,
+            Object::Tree {
+                id: snapshot.tree.to_hex(),
+            },
+        );
+        assert_eq!(tree["nodes"][0]["name"], "file");
+
+        let NodeKind::File { content, .. } = &repository
+            .read_tree(&snapshot.tree)
+            .nodes
+            .pop_first()
+            .unwrap()
+            .kind
+        else {
+            panic!("not a file");
+        };
+        let blob = cat(
+            &repository,
+            Object::Blob {
+                id: content[0].to_hex(),
+            },
+        );
+        assert_eq!(blob, b"some file contents");
+
+        let location = repository.locate(&content[0]).unwrap();
+        let pack = cat_json(
+            &repository,
+            Object::Pack {
+                id: location.pack.to_hex()[..8].to_string(),
+            },
+        );
+        assert_eq!(pack[0]["i"], content[0].to_hex());
+
+        let indexed = fs::read_dir(dir.path().join("repo/index"))
+            .unwrap()
+            .flat_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                let index = cat_json(
+                    &repository,
+                    Object::Index {
+                        id: name[..8].to_string(),
+                    },
+                );
+                index["packs"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|pack| pack["id"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(indexed.contains(&location.pack.to_hex()));
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+
+use clap::Subcommand;
+use jiff::Timestamp;
+
+use crate::pack;
+use crate::repo::{BlobClass, Dictionary, Hash, Repository};
+
+/// Most blobs read to train a dictionary from.
+const TRAINING_SAMPLES_MAX: usize = 4096;
+
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// list the dictionaries of the repository
+    List,
+    /// train new dictionaries from the blobs in the repository and remove the
+    /// ones no blob needs anymore
+    Train,
+}
+
+pub fn run(repository: &Repository, action: Action) {
+    match action {
+        Action::List => {
+            let current =
+                BlobClass::ALL.map(|class| repository.current_dictionary(class).map(|(id, _)| id));
+
+            for (id, dictionary) in repository.dictionaries() {
+                let marker = if current.contains(&Some(id)) {
+                    '*'
+                } else {
+                    ' '
+                };
+
+                println!(
+                    "{}{}  {:?}  {}  {}",
+                    marker,
+                    &id.to_hex()[..8],
+                    dictionary.class,
+                    Timestamp::from_second(dictionary.created).unwrap(),
+                    dictionary.data.len()
+                );
+            }
+        }
+        Action::Train => retrain(repository),
+    }
+}
+
+/// Trains a new dictionary for each class with enough blobs to learn from,
+/// then removes the dictionaries which are neither used by any indexed blob
+/// nor from the last two generations. Backups running while it trains may
+/// still be writing packs with the dictionaries current when it started, so
+/// those are only rotated out by the retrain after. Run after rewriting packs,
+/// as pruning does, blobs move onto the new dictionaries and the old ones are
+/// rotated out.
+pub fn retrain(repository: &Repository) {
+    let mut kept = BlobClass::ALL
+        .into_iter()
+        .filter_map(|class| repository.current_dictionary(class).map(|(id, _)| id))
+        .collect::<HashSet<Hash>>();
+
+    for class in BlobClass::ALL {
+        let samples = samples(repository, class);
+
+        let Some(data) = pack::train_dictionary(&samples) else {
+            println!("not enough {:?} blobs to train a dictionary from", class);
+            continue;
+        };
+
+        let id = repository.write_dictionary(&Dictionary {
+            class,
+            created: Timestamp::now().as_second(),
+            data,
+        });
+        kept.insert(id);
+
+        println!(
+            "trained {:?} dictionary {} from {} blobs",
+            class,
+            &id.to_hex()[..8],
+            samples.len()
+        );
+    }
+
+    let used = repository
+        .indexes()
+        .iter()
+        .flat_map(|index| &index.packs)
+        .flat_map(|pack| &pack.blobs)
+        .filter_map(|blob| blob.kind.dictionary())
+        .collect::<HashSet<Hash>>();
+
+    for (id, _) in repository.dictionaries() {
+        if !used.contains(&id) && !kept.contains(&id) {
+            repository.remove_dictionary(&id);
+            println!("removed dictionary {}", &id.to_hex()[..8]);
+        }
+    }
+}
+
+/// Reads an evenly spread selection of the blobs of a class.
+fn samples(repository: &Repository, class: BlobClass) -> Vec<Vec<u8>> {
+    let ids = repository
+        .indexes()
+        .into_iter()
+        .flat_map(|index| index.packs)
+        .flat_map(|pack| pack.blobs)
+        .filter(|blob| blob.kind.class() == class)
+        .filter(|blob| match class {
+            BlobClass::Tree => true,
+            BlobClass::Data => {
+                let size = blob
+                    .length_uncompressed
+                    .map_or(blob.length, |size| size.get());
+                size <= pack::DICTIONARY_BLOB_MAX
+            }
+        })
+        .map(|blob| blob.id)
+        .collect::<HashSet<_>>();
+
+    let mut ids = ids.into_iter().collect::<Vec<_>>();
+    ids.sort();
+
+    let step = ids.len().div_ceil(TRAINING_SAMPLES_MAX).max(1);
+    ids.iter()
+        .step_by(step)
+        .map(|id| repository.read_blob(id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use super::*;
+    use crate::cmd::{self, backup};
+    use crate::pack::Compression;
+    use crate::repo::{ChunkerParams, Cipher, Key, NodeKind, PackSize};
+
+    /// Text-like file contents which share enough to be worth a dictionary.
+    fn contents(i: usize) -> Vec<u8> {
+        format!(
+            "[entry {}]\nname = \"file {}\"\nvalue = {}\n",
+            i,
+            i % 7,
+            i * 31
+        )
+        .repeat(20 + i % 13)
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_retrain_backup_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, root) = (dir.path().join("source"), dir.path().join("repo"));
+        fs::create_dir_all(&source).unwrap();
+        for i in 0..300 {
+            fs::write(source.join(format!("{}.toml", i)), contents(i)).unwrap();
+        }
+
+        let key = Key::generate();
+        let repository = Repository::init(
+            &root,
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = backup::Options {
+            compression: Compression::Auto,
+            inline_size: 0,
+            deterministic: false,
+        };
+        backup::run(&repository, &source, options);
+
+        retrain(&repository);
+        let first = repository.current_dictionary(BlobClass::Data).unwrap().0;
+
+        // A backup started before the next retrain still packs blobs with the
+        // first dictionary, so that one survives it.
+        retrain(&repository);
+        assert!(repository.dictionaries().iter().any(|(id, _)| *id == first));
+
+        for i in 300..400 {
+            fs::write(source.join(format!("{}.toml", i)), contents(i)).unwrap();
+        }
+        backup::run(&repository, &source, options);
+
+        let blobs = repository
+            .indexes()
+            .into_iter()
+            .flat_map(|index| index.packs)
+            .flat_map(|pack| pack.blobs)
+            .collect::<Vec<_>>();
+        assert!(blobs.iter().any(|blob| blob.kind.dictionary().is_some()));
+
+        let repository = Repository::open(&root, key);
+
+        let (_, snapshot) = repository
+            .snapshots()
+            .into_iter()
+            .max_by_key(|(_, snapshot)| repository.read_tree(&snapshot.tree).nodes.len())
+            .unwrap();
+        for i in 0..400 {
+            let node = cmd::lookup(&repository, snapshot.tree, &format!("{}.toml", i));
+            let NodeKind::File { content, inline } = node.kind else {
+                panic!("not a file");
+            };
+
+            let mut data = Vec::new();
+            repository
+                .content_reader(&content, inline.as_deref())
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(data, contents(i));
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+use crate::repo::{Hash, Node, NodeKind, Repository};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Removed,
+    Modified,
+    Metadata,
+}
+
+impl Change {
+    fn marker(self) -> char {
+        match self {
+            Change::Added => '+',
+            Change::Removed => '-',
+            Change::Modified => 'M',
+            Change::Metadata => 'U',
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry {
+    path: String,
+    change: Change,
+}
+
+#[derive(Default, Serialize)]
+struct Summary {
+    added: usize,
+    removed: usize,
+    modified: usize,
+    metadata: usize,
+    blobs_added: usize,
+    blobs_removed: usize,
+    bytes_added: u64,
+    bytes_removed: u64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    changes: Vec<Entry>,
+    summary: Summary,
+}
+
+/// Walks two trees side by side, skipping any pair of subtrees with the same
+/// id. Data blobs are only collected from the parts which differ, so blobs
+/// that merely moved into an unchanged subtree may be counted as removed.
+/// Inline contents aren't shared between files, so they are simply summed.
+struct Differ<'a> {
+    repository: &'a Repository,
+    changes: Vec<Entry>,
+    blobs_a: HashSet<Hash>,
+    blobs_b: HashSet<Hash>,
+    inline_a: u64,
+    inline_b: u64,
+}
+
+impl Differ<'_> {
+    fn diff_trees(&mut self, a: &Hash, b: &Hash, prefix: &str) {
+        if a == b {
+            return;
+        }
+
+        let a = self.repository.read_tree(a);
+        let b = self.repository.read_tree(b);
+        let mut a = a.nodes.into_iter().peekable();
+        let mut b = b.nodes.into_iter().peekable();
+
+        loop {
+            let order = match (a.peek(), b.peek()) {
+                (Some(x), Some(y)) => x.cmp(y),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+
+            match order {
+                Ordering::Less => {
+                    let node = a.next().unwrap();
+                    self.report_all(&node, prefix, Change::Removed);
+                }
+                Ordering::Greater => {
+                    let node = b.next().unwrap();
+                    self.report_all(&node, prefix, Change::Added);
+                }
+                Ordering::Equal => {
+                    let (x, y) = (a.next().unwrap(), b.next().unwrap());
+                    self.diff_nodes(&x, &y, prefix);
+                }
+            }
+        }
+    }
+
+    fn diff_nodes(&mut self, a: &Node, b: &Node, prefix: &str) {
+        let path = format!("{}/{}", prefix, a.name);
+
+        match (&a.kind, &b.kind) {
+            (NodeKind::Dir { subtree: x }, NodeKind::Dir { subtree: y }) => {
+                if metadata_differs(a, b) {
+                    self.report(&path, Change::Metadata);
+                }
+
+                self.diff_trees(x, y, &path);
+            }
+            (
+                NodeKind::File {
+                    content: x,
+                    inline: inline_x,
+                },
+                NodeKind::File {
+                    content: y,
+                    inline: inline_y,
+                },
+            ) => {
+                if x != y || inline_x != inline_y {
+                    self.blobs_a.extend(x);
+                    self.blobs_b.extend(y);
+                    self.inline_a += inline_x.as_ref().map_or(0, |data| data.len() as u64);
+                    self.inline_b += inline_y.as_ref().map_or(0, |data| data.len() as u64);
+                    self.report(&path, Change::Modified);
+                } else if metadata_differs(a, b) {
+                    self.report(&path, Change::Metadata);
+                }
+            }
+            (
+                NodeKind::Symlink { link_target: x, .. },
+                NodeKind::Symlink { link_target: y, .. },
+            ) => {
+                if x != y {
+                    self.report(&path, Change::Modified);
+                } else if metadata_differs(a, b) {
+                    self.report(&path, Change::Metadata);
+                }
+            }
+            (NodeKind::Special { rdev: x }, NodeKind::Special { rdev: y })
+                if a.mode & libc::S_IFMT == b.mode & libc::S_IFMT =>
+            {
+                if x != y {
+                    self.report(&path, Change::Modified);
+                } else if metadata_differs(a, b) {
+                    self.report(&path, Change::Metadata);
+                }
+            }
+            _ => {
+                self.report_all(a, prefix, Change::Removed);
+                self.report_all(b, prefix, Change::Added);
+            }
+        }
+    }
+
+    /// Reports a node and everything below it as added or removed.
+    fn report_all(&mut self, node: &Node, prefix: &str, change: Change) {
+        let path = format!("{}/{}", prefix, node.name);
+        self.report(&path, change);
+
+        match &node.kind {
+            NodeKind::File { content, inline } => {
+                let inline = inline.as_ref().map_or(0, |data| data.len() as u64);
+                match change {
+                    Change::Removed => {
+                        self.blobs_a.extend(content);
+                        self.inline_a += inline;
+                    }
+                    _ => {
+                        self.blobs_b.extend(content);
+                        self.inline_b += inline;
+                    }
+                }
+            }
+            NodeKind::Dir { subtree } => {
+                let tree = self.repository.read_tree(subtree);
+                for child in &tree.nodes {
+                    self.report_all(child, &path, change);
+                }
+            }
+            NodeKind::Symlink { .. } | NodeKind::Special { .. } => {}
+        }
+    }
+
+    fn report(&mut self, path: &str, change: Change) {
+        self.changes.push(Entry {
+            path: path.to_owned(),
+            change,
+        });
+    }
+}
+
+fn metadata_differs(a: &Node, b: &Node) -> bool {
+    a.mode != b.mode || a.uid != b.uid || a.gid != b.gid || a.user != b.user || a.mtime != b.mtime
+}
+
+pub fn run(repository: &Repository, a: &str, b: &str, json: bool) {
+    let (_, a) = repository.find_snapshot(a);
+    let (_, b) = repository.find_snapshot(b);
+    let Report { changes, summary } = diff(repository, &a.tree, &b.tree);
+
+    if json {
+        let report = Report { changes, summary };
+        println!("{}", serde_json::to_string(&report).unwrap());
+        return;
+    }
+
+    for entry in &changes {
+        println!("{}    {}", entry.change.marker(), entry.path);
+    }
+
+    println!();
+    println!(
+        "{} added, {} removed, {} modified, {} metadata changed",
+        summary.added, summary.removed, summary.modified, summary.metadata
+    );
+    println!(
+        "data: {} blobs added ({} bytes), {} blobs removed ({} bytes)",
+        summary.blobs_added, summary.bytes_added, summary.blobs_removed, summary.bytes_removed
+    );
+}
+
+/// Compares two root trees. Bytes added and removed count both blobs and
+/// inline contents.
+fn diff(repository: &Repository, a: &Hash, b: &Hash) -> Report {
+    let mut differ = Differ {
+        repository,
+        changes: Vec::new(),
+        blobs_a: HashSet::new(),
+        blobs_b: HashSet::new(),
+        inline_a: 0,
+        inline_b: 0,
+    };
+
+    differ.diff_trees(a, b, "");
+
+    let mut summary = Summary::default();
+    for entry in &differ.changes {
+        match entry.change {
+            Change::Added => summary.added += 1,
+            Change::Removed => summary.removed += 1,
+            Change::Modified => summary.modified += 1,
+            Change::Metadata => summary.metadata += 1,
+        }
+    }
+
+    let blob_size = |id: &Hash| repository.locate(id).unwrap().size() as u64;
+
+    for id in differ.blobs_b.difference(&differ.blobs_a) {
+        summary.blobs_added += 1;
+        summary.bytes_added += blob_size(id);
+    }
+
+    for id in differ.blobs_a.difference(&differ.blobs_b) {
+        summary.blobs_removed += 1;
+        summary.bytes_removed += blob_size(id);
+    }
+
+    summary.bytes_added += differ.inline_b;
+    summary.bytes_removed += differ.inline_a;
+
+    Report {
+        changes: differ.changes,
+        summary,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::cmd::backup;
+    use crate::pack::Compression;
+    use crate::repo::{ChunkerParams, Cipher, Key, PackSize};
+
+    fn random(len: usize, seed: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; len];
+        blake3::Hasher::new()
+            .update(seed)
+            .finalize_xof()
+            .fill(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_diff_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, root) = (dir.path().join("source"), dir.path().join("repo"));
+        fs::create_dir_all(source.join("same")).unwrap();
+        fs::create_dir_all(source.join("gone")).unwrap();
+        fs::write(source.join("same/a"), b"unchanged").unwrap();
+        fs::write(source.join("same/b"), random(50_000, b"same")).unwrap();
+        fs::write(source.join("gone/x"), b"removed").unwrap();
+        fs::write(source.join("inline"), b"old").unwrap();
+        fs::write(source.join("big"), random(100_000, b"old")).unwrap();
+        fs::write(source.join("kind"), b"a file").unwrap();
+
+        let key = Key::generate();
+        let repository = Repository::init(
+            &root,
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = backup::Options {
+            compression: Compression::Auto,
+            inline_size: 128,
+            deterministic: false,
+        };
+        backup::run(&repository, &source, options);
+        let (_, first) = repository.snapshots().pop().unwrap();
+
+        fs::remove_dir_all(source.join("gone")).unwrap();
+        fs::remove_file(source.join("kind")).unwrap();
+        fs::create_dir_all(source.join("kind")).unwrap();
+        fs::write(source.join("kind/inner"), b"now a dir").unwrap();
+        fs::create_dir_all(source.join("new")).unwrap();
+        fs::write(source.join("new/y"), b"added").unwrap();
+        fs::write(source.join("inline"), b"new contents").unwrap();
+        fs::write(source.join("big"), random(100_000, b"new")).unwrap();
+        backup::run(&repository, &source, options);
+
+        let repository = Repository::open(&root, key);
+        let a = first.tree;
+        let b = repository
+            .snapshots()
+            .into_iter()
+            .map(|(_, snapshot)| snapshot.tree)
+            .find(|tree| *tree != a)
+            .unwrap();
+
+        let report = diff(&repository, &a, &b);
+        let mut changes = report
+            .changes
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.change))
+            .collect::<Vec<_>>();
+        changes.sort_by_key(|&(path, _)| path);
+        assert_eq!(
+            changes,
+            [
+                ("/big", Change::Modified),
+                ("/gone", Change::Removed),
+                ("/gone/x", Change::Removed),
+                ("/inline", Change::Modified),
+                ("/kind", Change::Removed),
+                ("/kind", Change::Added),
+                ("/kind/inner", Change::Added),
+                ("/new", Change::Added),
+                ("/new/y", Change::Added),
+            ]
+        );
+
+        let summary = &report.summary;
+        assert_eq!(
+            (summary.added, summary.removed, summary.modified),
+            (4, 3, 2)
+        );
+        assert_eq!(
+            summary.bytes_added,
+            100_000 + (b"new contents".len() + b"now a dir".len() + b"added".len()) as u64
+        );
+        assert_eq!(
+            summary.bytes_removed,
+            100_000 + (b"old".len() + b"removed".len() + b"a file".len()) as u64
+        );
+
+        // Equal trees, like the unchanged `same` above, have no changes.
+        let report = diff(&repository, &a, &a);
+        assert!(report.changes.is_empty());
+        assert_eq!(
+            (report.summary.bytes_added, report.summary.bytes_removed),
+            (0, 0)
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use std::ffi::OsStr;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+
+use clap::ValueEnum;
+use jiff::Timestamp;
+use jiff::tz::TimeZone;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+use crate::repo::{Hash, Node, NodeKind, Repository};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Archive {
+    Tar,
+    Zip,
+}
+
+/// Writes a file from a snapshot to stdout, or a directory as an archive in
+/// which entries are named relative to the directory containing it.
+pub fn run(repository: &Repository, snapshot: &str, path: &str, archive: Archive) {
+    let (_, snapshot) = repository.find_snapshot(snapshot);
+    let node = super::lookup(repository, snapshot.tree, path);
+    write(repository, &node, path, archive, &mut io::stdout().lock());
+}
+
+fn write(repository: &Repository, node: &Node, path: &str, archive: Archive, out: &mut dyn Write) {
+    match (&node.kind, archive) {
+        (NodeKind::File { content, inline }, _) => {
+            io::copy(
+                &mut repository.content_reader(content, inline.as_deref()),
+                out,
+            )
+            .unwrap();
+        }
+        (NodeKind::Symlink { .. }, _) => panic!("{:?} is a symlink", path),
+        (NodeKind::Special { .. }, _) => panic!("{:?} is not a regular file", path),
+        (NodeKind::Dir { subtree }, Archive::Tar) => {
+            let mut builder = tar::Builder::new(out);
+            let prefix = node.name.as_bytes().to_vec();
+
+            if !prefix.is_empty() {
+                append_tar(repository, &mut builder, &prefix, node);
+            }
+
+            walk(repository, subtree, &prefix, &mut |path, node| {
+                append_tar(repository, &mut builder, path, node);
+            });
+
+            builder.into_inner().unwrap().flush().unwrap();
+        }
+        (NodeKind::Dir { subtree }, Archive::Zip) => {
+            // Zip archives are finished with a central directory that points
+            // back into the archive, so it is spooled to a file before being
+            // copied to stdout.
+            let mut writer = ZipWriter::new(tempfile::tempfile().unwrap());
+            let prefix = node.name.as_bytes().to_vec();
+
+            if !prefix.is_empty() {
+                append_zip(repository, &mut writer, &prefix, node);
+            }
+
+            walk(repository, subtree, &prefix, &mut |path, node| {
+                append_zip(repository, &mut writer, path, node);
+            });
+
+            let mut file = writer.finish().unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+
+            io::copy(&mut file, out).unwrap();
+        }
+    }
+}
+
+/// Visits every node below a tree in depth first order, along with its path
+/// relative to the start of the walk.
+fn walk(repository: &Repository, id: &Hash, prefix: &[u8], visit: &mut dyn FnMut(&[u8], &Node)) {
+    let tree = repository.read_tree(id);
+
+    for node in &tree.nodes {
+        let mut path = prefix.to_vec();
+        if !path.is_empty() {
+            path.push(b'/');
+        }
+
+        path.extend_from_slice(node.name.as_bytes());
+        visit(&path, node);
+
+        if let NodeKind::Dir { subtree } = &node.kind {
+            walk(repository, subtree, &path, visit);
+        }
+    }
+}
+
+fn append_tar<W: Write>(
+    repository: &Repository,
+    builder: &mut tar::Builder<W>,
+    path: &[u8],
+    node: &Node,
+) {
+    let path = OsStr::from_bytes(path);
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(node.mode & 0o7777);
+    header.set_uid(node.uid as u64);
+    header.set_gid(node.gid as u64);
+    header.set_mtime(node.mtime.max(0) as u64);
+
+    if !node.user.is_empty() {
+        header.set_username(&node.user).unwrap();
+    }
+
+    match &node.kind {
+        NodeKind::File { content, inline } => {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(super::node_size(repository, node));
+            let reader = repository.content_reader(content, inline.as_deref());
+            builder.append_data(&mut header, path, reader).unwrap();
+        }
+        NodeKind::Dir { .. } => {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            builder.append_data(&mut header, path, io::empty()).unwrap();
+        }
+        NodeKind::Symlink { link_target, .. } => {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            let target = link_target.to_bytes();
+            builder
+                .append_link(&mut header, path, OsStr::from_bytes(&target))
+                .unwrap();
+        }
+        NodeKind::Special { rdev } => {
+            let entry_type = match node.mode & libc::S_IFMT {
+                libc::S_IFCHR => tar::EntryType::Char,
+                libc::S_IFBLK => tar::EntryType::Block,
+                libc::S_IFIFO => tar::EntryType::Fifo,
+                _ => {
+                    log::warn!("skipping socket {:?}, tar can't hold sockets", path);
+                    return;
+                }
+            };
+
+            header.set_entry_type(entry_type);
+            header.set_size(0);
+            header.set_device_major(libc::major(*rdev)).unwrap();
+            header.set_device_minor(libc::minor(*rdev)).unwrap();
+            builder.append_data(&mut header, path, io::empty()).unwrap();
+        }
+    }
+}
+
+fn append_zip<W: Write + Seek>(
+    repository: &Repository,
+    writer: &mut ZipWriter<W>,
+    path: &[u8],
+    node: &Node,
+) {
+    let name = String::from_utf8_lossy(path);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(node.mode & 0o7777)
+        .last_modified_time(zip_time(node.mtime))
+        .large_file(true);
+
+    match &node.kind {
+        NodeKind::File { content, inline } => {
+            writer.start_file(name, options).unwrap();
+            io::copy(
+                &mut repository.content_reader(content, inline.as_deref()),
+                writer,
+            )
+            .unwrap();
+        }
+        NodeKind::Dir { .. } => {
+            writer.add_directory(name, options).unwrap();
+        }
+        NodeKind::Symlink { link_target, .. } => {
+            let target = String::from_utf8_lossy(&link_target.to_bytes()).into_owned();
+            writer.add_symlink(name, target, options).unwrap();
+        }
+        NodeKind::Special { .. } => {
+            log::warn!(
+                "skipping {:?}, zip can't hold device nodes, fifos or sockets",
+                name
+            );
+        }
+    }
+}
+
+/// Converts a unix timestamp into the local time zip entries store, clamped to
+/// the range the format can represent.
+fn zip_time(mtime: i64) -> DateTime {
+    let time = Timestamp::from_second(mtime)
+        .unwrap()
+        .to_zoned(TimeZone::system())
+        .datetime();
+
+    DateTime::from_date_and_time(
+        time.year().clamp(1980, 2107) as u16,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Cursor, Read};
+    use std::path::Path;
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::cmd::backup;
+    use crate::pack::Compression;
+    use crate::repo::{ChunkerParams, Cipher, Key, PackSize};
+
+    fn dumped(repository: &Repository, path: &str, archive: Archive) -> Vec<u8> {
+        let (_, snapshot) = repository.snapshots().pop().unwrap();
+        let node = crate::cmd::lookup(repository, snapshot.tree, path);
+        let mut out = Vec::new();
+        write(repository, &node, path, archive, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("dir/sub")).unwrap();
+        let mut big = vec![0; 300_000];
+        blake3::Hasher::new().finalize_xof().fill(&mut big);
+        fs::write(source.join("dir/big"), &big).unwrap();
+        fs::write(source.join("dir/sub/small"), b"inline").unwrap();
+        std::os::unix::fs::symlink("sub/small", source.join("dir/link")).unwrap();
+
+        let repository = Repository::init(
+            &dir.path().join("repo"),
+            Key::generate(),
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = backup::Options {
+            compression: Compression::Auto,
+            inline_size: 128,
+            deterministic: false,
+        };
+        backup::run(&repository, &source, options);
+
+        assert_eq!(dumped(&repository, "dir/big", Archive::Tar), big);
+        assert_eq!(
+            dumped(&repository, "dir/sub/small", Archive::Zip),
+            b"inline"
+        );
+
+        // Entries are named relative to the directory containing the one
+        // dumped.
+        let tar = dumped(&repository, "dir", Archive::Tar);
+        let mut archive = tar::Archive::new(&tar[..]);
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_string();
+            let kind = entry.header().entry_type();
+            let target = entry.link_name().unwrap().map(|target| target.into_owned());
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            entries.push((path, kind, target, data));
+        }
+
+        let names = entries
+            .iter()
+            .map(|entry| entry.0.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["dir", "dir/big", "dir/link", "dir/sub", "dir/sub/small"]
+        );
+        assert_eq!(entries[0].1, tar::EntryType::Directory);
+        assert_eq!(entries[1].3, big);
+        assert_eq!(entries[2].1, tar::EntryType::Symlink);
+        assert_eq!(entries[2].2.as_deref(), Some(Path::new("sub/small")));
+        assert_eq!(entries[4].3, b"inline");
+
+        let zip = dumped(&repository, "dir", Archive::Zip);
+        let mut archive = ZipArchive::new(Cursor::new(zip)).unwrap();
+        let mut data = Vec::new();
+        archive
+            .by_name("dir/big")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, big);
+        assert!(archive.by_name("dir/sub/").unwrap().is_dir());
+        let mut target = String::new();
+        let mut link = archive.by_name("dir/link").unwrap();
+        assert!(link.is_symlink());
+        link.read_to_string(&mut target).unwrap();
+        assert_eq!(target, "sub/small");
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use jiff::Timestamp;
+
+use crate::repo::{Hash, Node, NodeKind, Repository, Snapshot};
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub pattern: Option<String>,
+    pub newer: Option<i64>,
+    pub older: Option<i64>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub blobs: Vec<String>,
+    pub trees: Vec<String>,
+}
+
+impl Filter {
+    fn matches(&self, repository: &Repository, node: &Node) -> bool {
+        if let Some(pattern) = &self.pattern {
+            if !glob_match(pattern.as_bytes(), node.name.as_bytes()) {
+                return false;
+            }
+        }
+
+        if self.newer.is_some_and(|newer| node.mtime < newer) {
+            return false;
+        }
+
+        if self.older.is_some_and(|older| node.mtime >= older) {
+            return false;
+        }
+
+        if self.min_size.is_some() || self.max_size.is_some() {
+            let NodeKind::File { .. } = node.kind else {
+                return false;
+            };
+
+            let size = super::node_size(repository, node);
+            if self.min_size.is_some_and(|min| size < min) {
+                return false;
+            }
+
+            if self.max_size.is_some_and(|max| size > max) {
+                return false;
+            }
+        }
+
+        if !self.blobs.is_empty() {
+            let NodeKind::File { content, .. } = &node.kind else {
+                return false;
+            };
+
+            if !content.iter().any(|id| hash_matches(&self.blobs, id)) {
+                return false;
+            }
+        }
+
+        if !self.trees.is_empty() {
+            let NodeKind::Dir { subtree } = &node.kind else {
+                return false;
+            };
+
+            if !hash_matches(&self.trees, subtree) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+struct Match {
+    path: String,
+    node: Node,
+}
+
+/// Tree walker which remembers the matches found below every tree it has
+/// visited, so subtrees shared between directories or snapshots are only read
+/// and searched once.
+struct Finder<'a> {
+    repository: &'a Repository,
+    filter: &'a Filter,
+    memo: HashMap<Hash, Rc<Vec<Match>>>,
+}
+
+impl Finder<'_> {
+    fn search(&mut self, id: &Hash) -> Rc<Vec<Match>> {
+        if let Some(matches) = self.memo.get(id) {
+            return matches.clone();
+        }
+
+        let tree = self.repository.read_tree(id);
+        let mut matches = Vec::new();
+
+        for node in tree.nodes {
+            let path = format!("/{}", node.name);
+            let below = match &node.kind {
+                NodeKind::Dir { subtree } => Some(self.search(subtree)),
+                _ => None,
+            };
+
+            if self.filter.matches(self.repository, &node) {
+                matches.push(Match {
+                    path: path.clone(),
+                    node: node.clone(),
+                });
+            }
+
+            if let Some(below) = below {
+                matches.extend(below.iter().map(|found| Match {
+                    path: format!("{}{}", path, found.path),
+                    node: found.node.clone(),
+                }));
+            }
+        }
+
+        let matches = Rc::new(matches);
+        self.memo.insert(*id, matches.clone());
+        matches
+    }
+}
+
+pub fn run(repository: &Repository, snapshots: &[String], filter: &Filter) {
+    let mut finder = Finder {
+        repository,
+        filter,
+        memo: HashMap::new(),
+    };
+
+    let snapshots = if snapshots.is_empty() {
+        repository.snapshots()
+    } else {
+        snapshots
+            .iter()
+            .map(|query| repository.find_snapshot(query))
+            .collect()
+    };
+
+    for (id, snapshot) in snapshots {
+        let mut matches = Vec::new();
+
+        if !filter.trees.is_empty() && filter.matches(repository, &root_node(repository, &snapshot))
+        {
+            matches.push("/".to_owned());
+        }
+
+        matches.extend(finder.search(&snapshot.tree).iter().map(|m| m.path.clone()));
+
+        if matches.is_empty() {
+            continue;
+        }
+
+        let time = Timestamp::from_second(snapshot.time).unwrap();
+        println!(
+            "found {} matches in snapshot {} from {}",
+            matches.len(),
+            &id.to_hex()[..8],
+            time
+        );
+
+        for path in matches {
+            println!("{}", path);
+        }
+
+        println!();
+    }
+}
+
+/// The root directory of a snapshot, with the metadata of the directory
+/// backed up if the snapshot has it.
+fn root_node(repository: &Repository, snapshot: &Snapshot) -> Node {
+    let node = super::lookup(repository, snapshot.tree, "");
+    match &snapshot.root {
+        Some(root) => Node {
+            name: node.name,
+            kind: node.kind,
+            ..root.clone()
+        },
+        None => node,
+    }
+}
+
+fn hash_matches(prefixes: &[String], id: &Hash) -> bool {
+    let hex = id.to_hex();
+    prefixes
+        .iter()
+        .any(|prefix| hex.starts_with(prefix.as_str()))
+}
+
+/// Matches a name against a shell style glob supporting `*`, `?` and bracket
+/// expressions such as `[a-z]` or `[!0-9]`. Both sides are raw bytes since
+/// names need not be valid UTF-8.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            Some(b'[') => {
+                if let Some((matched, len)) = match_class(&pattern[p..], name[n]) {
+                    if matched {
+                        p += len;
+                        n += 1;
+                        continue;
+                    }
+                } else if name[n] == b'[' {
+                    p += 1;
+                    n += 1;
+                    continue;
+                }
+            }
+            Some(&byte) if byte == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match backtrack {
+            Some((star, consumed)) => {
+                p = star + 1;
+                n = consumed + 1;
+                backtrack = Some((star, consumed + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// Matches a byte against the bracket expression at the start of `class`,
+/// returning whether it matched and the length of the expression, or `None`
+/// if the bracket is never closed.
+fn match_class(class: &[u8], byte: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(class.get(i), Some(b'!' | b'^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+
+    loop {
+        let &start = class.get(i)?;
+        if start == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+
+        first = false;
+
+        match (class.get(i + 1), class.get(i + 2)) {
+            (Some(b'-'), Some(&end)) if end != b']' => {
+                matched |= (start..=end).contains(&byte);
+                i += 3;
+            }
+            _ => {
+                matched |= start == byte;
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn test_glob_literal() {
+        assert!(glob_match(b"db.sqlite", b"db.sqlite"));
+        assert!(!glob_match(b"db.sqlite", b"db.sqlite3"));
+        assert!(!glob_match(b"db.sqlite", b"db"));
+    }
+
+    #[test]
+    fn test_glob_wildcards() {
+        assert!(glob_match(b"*.sqlite", b"db.sqlite"));
+        assert!(glob_match(b"*.sqlite", b".sqlite"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a*b*c", b"aXXbYYbc"));
+        assert!(!glob_match(b"*.sqlite", b"db.sqlite-wal"));
+        assert!(glob_match(b"file?.txt", b"file1.txt"));
+        assert!(!glob_match(b"file?.txt", b"file.txt"));
+    }
+
+    #[test]
+    fn test_glob_classes() {
+        assert!(glob_match(b"log[0-9]", b"log7"));
+        assert!(!glob_match(b"log[0-9]", b"logx"));
+        assert!(glob_match(b"log[!0-9]", b"logx"));
+        assert!(glob_match(b"[]]", b"]"));
+        assert!(glob_match(b"a[", b"a["));
+    }
+
+    #[test]
+    fn test_glob_non_utf8() {
+        assert!(glob_match(b"bad*", b"bad\xffname"));
+        assert!(glob_match(b"bad?name", b"bad\xffname"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use clap::Subcommand;
+use jiff::Timestamp;
+
+use crate::repo::{Kdf, Recipe, Repository};
+
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// add a key with a new password
+    Add,
+    /// list the keys of the repository
+    List,
+    /// remove a key, by id prefix
+    Remove { id: String },
+    /// change the password of the key used to open the repository
+    Passwd,
+    /// print the master key, for use with --key-file
+    Export,
+    /// rewrap the key used to open the repository with new kdf parameters
+    Upgrade {
+        /// calibrate the parameters to take about this many milliseconds to
+        /// unlock on this machine, instead of using the defaults
+        #[arg(long)]
+        unlock_time: Option<u64>,
+    },
+}
+
+/// Wraps the master key of a repository with a new password, recording who
+/// created it.
+pub fn new_recipe(repository: &Repository, password: &str) -> Recipe {
+    Recipe::new(
+        repository.key(),
+        password.as_bytes(),
+        Kdf::default(),
+        super::hostname(),
+        std::env::var("USER").unwrap_or_default(),
+    )
+    .unwrap()
+}
+
+pub fn run(repository: &Repository, password: Option<&str>, action: Action) {
+    match action {
+        Action::Add => {
+            let recipe = new_recipe(repository, &super::new_password());
+            let id = repository.write_key(&recipe);
+            println!("added key {}", &id.to_hex()[..8]);
+        }
+        Action::List => {
+            for (id, recipe) in repository.keys() {
+                let current = if repository.key_id() == Some(id) {
+                    '*'
+                } else {
+                    ' '
+                };
+
+                println!(
+                    "{}{}  {}@{}  {}  {}",
+                    current,
+                    &id.to_hex()[..8],
+                    recipe.username,
+                    recipe.hostname,
+                    Timestamp::from_second(recipe.created).unwrap(),
+                    recipe.kdf
+                );
+            }
+        }
+        Action::Remove { id } => {
+            let (id, _) = repository.find_key(&id);
+
+            if repository.key_id() == Some(id) {
+                panic!("refusing to remove the key used to open the repository");
+            }
+
+            if repository.keys().len() <= 1 {
+                panic!("refusing to remove the last key of the repository");
+            }
+
+            repository.remove_key(&id);
+            println!("removed key {}", &id.to_hex()[..8]);
+        }
+        Action::Passwd => {
+            let Some(old) = repository.key_id() else {
+                panic!("repository was not opened with a password");
+            };
+
+            // The new key is written before the old one is removed, so the
+            // repository is never left without a key.
+            let recipe = new_recipe(repository, &super::new_password());
+            let id = repository.write_key(&recipe);
+            repository.remove_key(&old);
+            println!("changed password, key is now {}", &id.to_hex()[..8]);
+        }
+        Action::Export => println!("{}", hex::encode(repository.key().bytes)),
+        Action::Upgrade { unlock_time } => {
+            let (Some(old), Some(password)) = (repository.key_id(), password) else {
+                panic!("repository was not opened with a password");
+            };
+
+            let kdf = match unlock_time {
+                Some(millis) => Kdf::calibrate(Duration::from_millis(millis)),
+                None => Kdf::default(),
+            };
+
+            let (_, recipe) = repository.find_key(&old.to_hex());
+            let recipe = Recipe::new(
+                repository.key(),
+                password.as_bytes(),
+                kdf,
+                recipe.hostname,
+                recipe.username,
+            )
+            .unwrap();
+
+            let id = repository.write_key(&recipe);
+            repository.remove_key(&old);
+            println!(
+                "upgraded key {} to {} using {}",
+                &old.to_hex()[..8],
+                &id.to_hex()[..8],
+                kdf
+            );
+        }
+    }
+}
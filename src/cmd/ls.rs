@@ -0,0 +1,243 @@
+use std::io::{self, Write};
+
+use jiff::Timestamp;
+use jiff::tz::TimeZone;
+use serde::Serialize;
+
+use crate::repo::{Hash, Node, NodeKind, Repository};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Short,
+    Long,
+    Json,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    path: &'a str,
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    user: &'a str,
+    size: u64,
+    mtime: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_target: Option<String>,
+}
+
+pub fn run(
+    repository: &Repository,
+    snapshot: &str,
+    path: Option<&str>,
+    recursive: bool,
+    format: Format,
+) {
+    let (_, snapshot) = repository.find_snapshot(snapshot);
+    let path = path.unwrap_or("/");
+    let node = super::lookup(repository, snapshot.tree, path);
+    let prefix = super::split_path(path)
+        .into_iter()
+        .map(|segment| format!("/{}", segment))
+        .collect::<String>();
+
+    let out = &mut io::stdout().lock();
+    match node.kind {
+        NodeKind::Dir { subtree } => {
+            list_tree(repository, &subtree, &prefix, recursive, format, out);
+        }
+        _ => write_node(repository, &prefix, &node, format, out),
+    }
+}
+
+fn list_tree(
+    repository: &Repository,
+    id: &Hash,
+    prefix: &str,
+    recursive: bool,
+    format: Format,
+    out: &mut dyn Write,
+) {
+    let tree = repository.read_tree(id);
+
+    for node in &tree.nodes {
+        let path = format!("{}/{}", prefix, node.name);
+        write_node(repository, &path, node, format, out);
+
+        if let (true, NodeKind::Dir { subtree }) = (recursive, &node.kind) {
+            list_tree(repository, subtree, &path, recursive, format, out);
+        }
+    }
+}
+
+fn write_node(
+    repository: &Repository,
+    path: &str,
+    node: &Node,
+    format: Format,
+    out: &mut dyn Write,
+) {
+    let size = super::node_size(repository, node);
+    // Times beyond what a timestamp can hold are shown as raw seconds.
+    let mtime = Timestamp::from_second(node.mtime).ok();
+    let link_target = match &node.kind {
+        NodeKind::Symlink { link_target, .. } => Some(link_target.to_string()),
+        _ => None,
+    };
+
+    match format {
+        Format::Short => writeln!(out, "{}", path).unwrap(),
+        Format::Long => {
+            let owner = match node.user.as_str() {
+                "" => node.uid.to_string(),
+                user => user.to_owned(),
+            };
+
+            let mtime = match mtime {
+                Some(mtime) => mtime
+                    .to_zoned(TimeZone::system())
+                    .strftime("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+                None => format!("{:>19}", node.mtime),
+            };
+
+            write!(
+                out,
+                "{} {:>8} {:>8} {:>12} {} {}",
+                format_mode(node.mode),
+                owner,
+                node.gid,
+                size,
+                mtime,
+                path
+            )
+            .unwrap();
+
+            match link_target {
+                Some(target) => writeln!(out, " -> {}", target).unwrap(),
+                None => writeln!(out).unwrap(),
+            }
+        }
+        Format::Json => {
+            let entry = Entry {
+                path,
+                name: node.name.to_string(),
+                kind: match node.kind {
+                    NodeKind::File { .. } => "file",
+                    NodeKind::Dir { .. } => "dir",
+                    NodeKind::Symlink { .. } => "symlink",
+                    NodeKind::Special { .. } => match node.mode & libc::S_IFMT {
+                        libc::S_IFCHR => "chardev",
+                        libc::S_IFBLK => "blockdev",
+                        libc::S_IFIFO => "fifo",
+                        _ => "socket",
+                    },
+                },
+                mode: node.mode,
+                uid: node.uid,
+                gid: node.gid,
+                user: &node.user,
+                size,
+                mtime: mtime.map_or_else(|| node.mtime.to_string(), |mtime| mtime.to_string()),
+                link_target,
+            };
+
+            serde_json::to_writer(&mut *out, &entry).unwrap();
+            writeln!(out).unwrap();
+        }
+    }
+}
+
+pub fn format_mode(mode: u32) -> String {
+    let kind = match mode & libc::S_IFMT {
+        libc::S_IFDIR => 'd',
+        libc::S_IFLNK => 'l',
+        libc::S_IFCHR => 'c',
+        libc::S_IFBLK => 'b',
+        libc::S_IFIFO => 'p',
+        libc::S_IFSOCK => 's',
+        _ => '-',
+    };
+
+    let mut out = String::with_capacity(10);
+    out.push(kind);
+
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        out.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{ChunkerParams, Cipher, Key, PackSize};
+    use crate::useg::{UPath, USeg};
+
+    fn node(name: &[u8], mtime: i64, user: &str) -> Node {
+        Node {
+            name: USeg::from_segment_bytes(name),
+            mode: libc::S_IFLNK | 0o777,
+            mtime,
+            atime: 0,
+            ctime: 0,
+            uid: 1234,
+            gid: 5678,
+            user: user.to_string(),
+            inode: 0,
+            kind: NodeKind::Symlink {
+                link_target: UPath::from_path(std::path::Path::new("target\n")),
+                links: 1,
+            },
+        }
+    }
+
+    fn written(repository: &Repository, node: &Node, format: Format) -> String {
+        let mut out = Vec::new();
+        let path = format!("/{}", node.name);
+        write_node(repository, &path, node, format, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = Repository::init(
+            dir.path(),
+            Key::generate(),
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+
+        let far = node(b"far\\future\n", i64::MAX, "");
+        assert_eq!(
+            written(&repository, &far, Format::Short),
+            "/far\\\\future\\x0a\n"
+        );
+        assert_eq!(
+            written(&repository, &far, Format::Long),
+            format!(
+                "lrwxrwxrwx     1234     5678            0 {} /far\\\\future\\x0a -> target\\x0a\n",
+                i64::MAX
+            )
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&written(&repository, &far, Format::Json)).unwrap();
+        assert_eq!(json["mtime"], i64::MAX.to_string());
+        assert_eq!(json["name"], "far\\\\future\\x0a");
+
+        let owned = node(b"owned", 0, "alice");
+        let long = written(&repository, &owned, Format::Long);
+        assert!(long.starts_with("lrwxrwxrwx    alice     5678            0 "));
+    }
+}
@@ -0,0 +1,333 @@
+pub mod backup;
+pub mod cat;
+pub mod dict;
+pub mod diff;
+pub mod dump;
+pub mod find;
+pub mod key;
+pub mod ls;
+pub mod mount;
+
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::{fs, process};
+
+use jiff::Timestamp;
+use jiff::civil::Date;
+use jiff::tz::TimeZone;
+
+use crate::repo::{Hash, Key, Node, NodeKind, Repository};
+use crate::useg::USeg;
+
+/// Splits a slash separated path within a snapshot into its segments.
+pub fn split_path(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect()
+}
+
+/// Walks `path` down from the root tree of a snapshot and returns the node it
+/// names. The root itself is represented by a nameless directory node.
+pub fn lookup(repository: &Repository, root: Hash, path: &str) -> Node {
+    let mut node = Node {
+        name: USeg::from_segment_bytes(&[]),
+        mode: libc::S_IFDIR | 0o755,
+        mtime: 0,
+        atime: 0,
+        ctime: 0,
+        uid: 0,
+        gid: 0,
+        user: String::new(),
+        inode: 0,
+        kind: NodeKind::Dir { subtree: root },
+    };
+
+    for segment in split_path(path) {
+        let NodeKind::Dir { subtree } = node.kind else {
+            panic!("{:?} is not a directory", path);
+        };
+
+        let tree = repository.read_tree(&subtree);
+        node = tree
+            .nodes
+            .into_iter()
+            .find(|node| node.name.as_bytes() == segment.as_bytes())
+            .unwrap_or_else(|| panic!("no such path in snapshot: {:?}", path));
+    }
+
+    node
+}
+
+/// Returns the size of the content of a node, zero for anything but files.
+pub fn node_size(repository: &Repository, node: &Node) -> u64 {
+    match &node.kind {
+        NodeKind::File { content, inline } => {
+            let blobs = content
+                .iter()
+                .map(|id| {
+                    repository
+                        .locate(id)
+                        .expect("blob missing from index")
+                        .size() as u64
+                })
+                .sum::<u64>();
+
+            blobs + inline.as_ref().map_or(0, |inline| inline.len() as u64)
+        }
+        _ => 0,
+    }
+}
+
+/// Parses either an RFC 3339 timestamp or a plain `YYYY-MM-DD` date, taken as
+/// midnight in the local timezone, into seconds since the unix epoch.
+pub fn parse_time(time: &str) -> i64 {
+    if let Ok(timestamp) = time.parse::<Timestamp>() {
+        return timestamp.as_second();
+    }
+
+    let date = time
+        .parse::<Date>()
+        .unwrap_or_else(|_| panic!("invalid time {:?}", time));
+
+    date.to_zoned(TimeZone::system())
+        .unwrap()
+        .timestamp()
+        .as_second()
+}
+
+/// Parses a byte size with an optional binary `K`, `M`, `G` or `T` suffix.
+pub fn parse_size(size: &str) -> u64 {
+    let (digits, shift) = match size.as_bytes().last() {
+        Some(b'k' | b'K') => (&size[..size.len() - 1], 10),
+        Some(b'm' | b'M') => (&size[..size.len() - 1], 20),
+        Some(b'g' | b'G') => (&size[..size.len() - 1], 30),
+        Some(b't' | b'T') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+
+    let value = digits
+        .parse::<u64>()
+        .unwrap_or_else(|_| panic!("invalid size {:?}", size));
+
+    value
+        .checked_mul(1 << shift)
+        .unwrap_or_else(|| panic!("size {:?} is too large", size))
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return String::new();
+    }
+
+    CStr::from_bytes_until_nul(&buf)
+        .unwrap()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Prompts for a password on the terminal. Without one this fails rather than
+/// read stdin, which may hold the data being backed up.
+pub fn read_password(prompt: &str) -> String {
+    rpassword::prompt_password(prompt).unwrap_or_else(|err| {
+        panic!(
+            "cannot prompt for a password without a terminal ({}), give it with \
+             --password-file, --password-command or {}",
+            err, PASSWORD_ENV
+        )
+    })
+}
+
+/// Prompts for a new password twice.
+pub fn new_password() -> String {
+    let password = read_password("enter new password: ");
+    if password.is_empty() {
+        panic!("password must not be empty");
+    }
+
+    if read_password("repeat password: ") != password {
+        panic!("passwords do not match");
+    }
+
+    password
+}
+
+const PASSWORD_ENV: &str = "CASB_PASSWORD";
+
+/// Where the secret which unlocks a repository comes from. The first source
+/// given wins, in the order `--key-file`, `--password-file`,
+/// `--password-command`, `CASB_PASSWORD` and finally an interactive prompt.
+#[derive(clap::Args, Debug)]
+pub struct Credentials {
+    /// open the repository with the hex encoded master key in this file,
+    /// bypassing key files
+    #[arg(long, global = true, conflicts_with_all = ["password_file", "password_command"])]
+    pub key_file: Option<PathBuf>,
+
+    /// read the password from the first line of this file
+    #[arg(long, global = true, conflicts_with = "password_command")]
+    pub password_file: Option<PathBuf>,
+
+    /// read the password from the first line printed by this shell command
+    #[arg(long, global = true)]
+    pub password_command: Option<String>,
+}
+
+impl Credentials {
+    /// Returns the password from a non-interactive source, if one is given.
+    fn given_password(&self) -> Option<String> {
+        if let Some(path) = &self.password_file {
+            let data = fs::read_to_string(path).unwrap();
+            return Some(first_line(&data));
+        }
+
+        if let Some(command) = &self.password_command {
+            let output = process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stderr(process::Stdio::inherit())
+                .output()
+                .unwrap();
+
+            if !output.status.success() {
+                panic!("password command failed with {}", output.status);
+            }
+
+            return Some(first_line(&String::from_utf8(output.stdout).unwrap()));
+        }
+
+        std::env::var(PASSWORD_ENV).ok()
+    }
+
+    pub fn password(&self) -> String {
+        self.given_password()
+            .unwrap_or_else(|| read_password("enter password for repository: "))
+    }
+
+    /// Like `password`, but asks twice when prompting since the password is
+    /// about to be set.
+    pub fn new_password(&self) -> String {
+        self.given_password().unwrap_or_else(new_password)
+    }
+
+    pub fn unlock(&self, repo: &Path) -> Repository {
+        self.unlock_with_password(repo).0
+    }
+
+    /// Opens a repository, also returning the password used unless it was
+    /// opened with a raw key.
+    pub fn unlock_with_password(&self, repo: &Path) -> (Repository, Option<String>) {
+        if let Some(path) = &self.key_file {
+            let data = fs::read_to_string(path).unwrap();
+            let bytes = hex::decode(data.trim())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .unwrap_or_else(|| panic!("{:?} does not contain a hex encoded key", path));
+
+            return (Repository::open(repo, Key { bytes }), None);
+        }
+
+        let password = self.password();
+        let repository = Repository::unlock(repo, password.as_bytes());
+        (repository, Some(password))
+    }
+}
+
+fn first_line(data: &str) -> String {
+    data.lines().next().unwrap_or_default().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{ChunkerParams, Cipher, Kdf, PackSize, Recipe};
+
+    fn credentials() -> Credentials {
+        Credentials {
+            key_file: None,
+            password_file: None,
+            password_command: None,
+        }
+    }
+
+    /// Makes a repository unlocked by the password `hunter2`.
+    fn repository(dir: &Path) -> (PathBuf, Key) {
+        let root = dir.join("repo");
+        let key = Key::generate();
+        let repository = Repository::init(
+            &root,
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let kdf = Kdf::Argon2id { m: 64, t: 1, p: 1 };
+        repository
+            .write_key(&Recipe::new(&key, b"hunter2", kdf, String::new(), String::new()).unwrap());
+        (root, key)
+    }
+
+    #[test]
+    fn test_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let (root, key) = repository(dir.path());
+
+        // Only the first line of a file or of a command's output is used.
+        let password_file = dir.path().join("password");
+        fs::write(&password_file, "hunter2\nsecond line\n").unwrap();
+        let (repository, password) = Credentials {
+            password_file: Some(password_file),
+            ..credentials()
+        }
+        .unlock_with_password(&root);
+        assert_eq!(repository.key().bytes, key.bytes);
+        assert_eq!(password.as_deref(), Some("hunter2"));
+
+        let (repository, password) = Credentials {
+            password_command: Some("printf 'hunter2\\nmore'".to_string()),
+            ..credentials()
+        }
+        .unlock_with_password(&root);
+        assert_eq!(repository.key().bytes, key.bytes);
+        assert_eq!(password.as_deref(), Some("hunter2"));
+
+        let key_file = dir.path().join("key");
+        fs::write(&key_file, format!("{}\n", hex::encode(key.bytes))).unwrap();
+        let (repository, password) = Credentials {
+            key_file: Some(key_file),
+            ..credentials()
+        }
+        .unlock_with_password(&root);
+        assert_eq!(repository.key().bytes, key.bytes);
+        assert_eq!(password, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "password command failed")]
+    fn test_failing_password_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let (root, _) = repository(dir.path());
+
+        Credentials {
+            password_command: Some("echo hunter2; exit 1".to_string()),
+            ..credentials()
+        }
+        .unlock(&root);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), 512);
+        assert_eq!(parse_size("4k"), 4096);
+        assert_eq!(parse_size("16M"), 16 << 20);
+        assert_eq!(parse_size("16777215T"), u64::MAX - ((1 << 40) - 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "too large")]
+    fn test_parse_size_overflow() {
+        parse_size("16777216T");
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::rc::Rc;
+
+use jiff::Timestamp;
+
+use crate::fuse::{self, Attr, DirEntry, Filesystem, ROOT_ID};
+use crate::repo::{Hash, Node, NodeKind, Repository, Snapshot};
+use crate::useg::USeg;
+
+/// Upper bound on the decoded data blobs kept in memory for reads.
+const BLOB_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+struct Inode {
+    parent: u64,
+    entry: Entry,
+}
+
+enum Entry {
+    /// A directory made up by the mount itself, such as `snapshots`.
+    Virtual {
+        mtime: i64,
+    },
+    Node(Node),
+}
+
+struct Handle {
+    content: Vec<Hash>,
+    /// Inline content, which comes before any blobs.
+    inline: Vec<u8>,
+    /// Offset of the first byte of every blob in the file, followed by the
+    /// size of the file.
+    offsets: Vec<u64>,
+}
+
+/// Keeps recently read data blobs so reads of neighbouring ranges, which the
+/// kernel issues in small pieces, only fetch and decrypt each blob once.
+struct BlobCache {
+    blobs: HashMap<Hash, Rc<Vec<u8>>>,
+    order: VecDeque<Hash>,
+    size: usize,
+}
+
+impl BlobCache {
+    fn get(&mut self, repository: &Repository, id: &Hash) -> Rc<Vec<u8>> {
+        if let Some(data) = self.blobs.get(id) {
+            let position = self.order.iter().position(|cached| cached == id).unwrap();
+            self.order.remove(position);
+            self.order.push_back(*id);
+            return data.clone();
+        }
+
+        let data = Rc::new(repository.read_blob(id));
+        self.size += data.len();
+        self.blobs.insert(*id, data.clone());
+        self.order.push_back(*id);
+
+        while self.size > BLOB_CACHE_SIZE && self.order.len() > 1 {
+            let evicted = self.order.pop_front().unwrap();
+            self.size -= self.blobs.remove(&evicted).unwrap().len();
+        }
+
+        data
+    }
+}
+
+/// Read-only view of every snapshot in a repository. Inodes are handed out as
+/// entries are first looked up and live for as long as the mount.
+struct SnapshotFs<'a> {
+    repository: &'a Repository,
+    inodes: Vec<Inode>,
+    children: HashMap<u64, Vec<(Vec<u8>, u64)>>,
+    handles: HashMap<u64, Handle>,
+    next_handle: u64,
+    blobs: BlobCache,
+    uid: u32,
+    gid: u32,
+}
+
+impl SnapshotFs<'_> {
+    fn allocate(&mut self, parent: u64, entry: Entry) -> u64 {
+        self.inodes.push(Inode { parent, entry });
+        self.inodes.len() as u64
+    }
+
+    fn inode(&self, ino: u64) -> Result<&Inode, i32> {
+        let index = ino.checked_sub(1).ok_or(libc::ENOENT)?;
+        self.inodes.get(index as usize).ok_or(libc::ENOENT)
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node, i32> {
+        match &self.inode(ino)?.entry {
+            Entry::Node(node) => Ok(node),
+            Entry::Virtual { .. } => Err(libc::EISDIR),
+        }
+    }
+
+    /// Lists a directory, reading its tree the first time it is visited.
+    fn children(&mut self, ino: u64) -> Result<&[(Vec<u8>, u64)], i32> {
+        if !self.children.contains_key(&ino) {
+            let NodeKind::Dir { subtree } = &self.node(ino)?.kind else {
+                return Err(libc::ENOTDIR);
+            };
+
+            let tree = self.repository.read_tree(subtree);
+            let mut children = Vec::new();
+
+            for node in tree.nodes {
+                let name = node.name.as_bytes().to_vec();
+                children.push((name, self.allocate(ino, Entry::Node(node))));
+            }
+
+            self.children.insert(ino, children);
+        }
+
+        Ok(&self.children[&ino])
+    }
+
+    fn attr(&self, ino: u64) -> Result<Attr, i32> {
+        let attr = match &self.inode(ino)?.entry {
+            Entry::Virtual { mtime } => Attr {
+                ino,
+                size: 0,
+                mode: libc::S_IFDIR | 0o555,
+                nlink: 2,
+                rdev: 0,
+                uid: self.uid,
+                gid: self.gid,
+                atime: *mtime,
+                mtime: *mtime,
+                ctime: *mtime,
+            },
+            Entry::Node(node) => {
+                let (size, nlink) = match &node.kind {
+                    NodeKind::File { .. } => (super::node_size(self.repository, node), 1),
+                    NodeKind::Dir { .. } => (0, 2),
+                    NodeKind::Symlink { link_target, .. } => {
+                        (link_target.to_bytes().len() as u64, 1)
+                    }
+                    NodeKind::Special { .. } => (0, 1),
+                };
+                let rdev = match node.kind {
+                    NodeKind::Special { rdev } => rdev,
+                    _ => 0,
+                };
+
+                Attr {
+                    ino,
+                    size,
+                    mode: node.mode,
+                    nlink,
+                    rdev,
+                    uid: node.uid,
+                    gid: node.gid,
+                    atime: node.atime,
+                    mtime: node.mtime,
+                    ctime: node.ctime,
+                }
+            }
+        };
+
+        Ok(attr)
+    }
+}
+
+impl Filesystem for SnapshotFs<'_> {
+    fn lookup(&mut self, parent: u64, name: &[u8]) -> Result<Attr, i32> {
+        let ino = self
+            .children(parent)?
+            .iter()
+            .find(|(child, _)| child == name)
+            .map(|(_, ino)| *ino)
+            .ok_or(libc::ENOENT)?;
+
+        self.attr(ino)
+    }
+
+    fn getattr(&mut self, ino: u64) -> Result<Attr, i32> {
+        self.attr(ino)
+    }
+
+    fn readlink(&mut self, ino: u64) -> Result<Vec<u8>, i32> {
+        match &self.node(ino)?.kind {
+            NodeKind::Symlink { link_target, .. } => Ok(link_target.to_bytes()),
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    fn open(&mut self, ino: u64) -> Result<u64, i32> {
+        let NodeKind::File { content, inline } = &self.node(ino)?.kind else {
+            return Err(libc::EISDIR);
+        };
+
+        let mut offsets = vec![inline.as_ref().map_or(0, |inline| inline.len() as u64)];
+        for id in content {
+            let size = self.repository.locate(id).unwrap().size() as u64;
+            offsets.push(offsets.last().unwrap() + size);
+        }
+
+        let handle = Handle {
+            content: content.clone(),
+            inline: inline.clone().unwrap_or_default(),
+            offsets,
+        };
+
+        self.next_handle += 1;
+        self.handles.insert(self.next_handle, handle);
+        Ok(self.next_handle)
+    }
+
+    fn read(&mut self, _ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32> {
+        let handle = self.handles.get(&fh).ok_or(libc::EBADF)?;
+        let end = (offset + size as u64).min(*handle.offsets.last().unwrap());
+        let mut data = Vec::with_capacity(size as usize);
+        let mut position = offset;
+
+        let inline_end = (handle.inline.len() as u64).min(end);
+        if position < inline_end {
+            data.extend_from_slice(&handle.inline[position as usize..inline_end as usize]);
+            position = inline_end;
+        }
+
+        while position < end {
+            let index = blob_at(&handle.offsets, position);
+            let start = handle.offsets[index];
+            let blob = self.blobs.get(self.repository, &handle.content[index]);
+            let from = (position - start) as usize;
+            let to = ((end - start) as usize).min(blob.len());
+
+            data.extend_from_slice(&blob[from..to]);
+            position = start + to as u64;
+        }
+
+        Ok(data)
+    }
+
+    fn release(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+    }
+
+    fn readdir(&mut self, ino: u64) -> Result<Vec<DirEntry>, i32> {
+        let parent = self.inode(ino)?.parent;
+        let mut entries = vec![
+            DirEntry {
+                ino,
+                mode: libc::S_IFDIR,
+                name: b".".to_vec(),
+            },
+            DirEntry {
+                ino: parent,
+                mode: libc::S_IFDIR,
+                name: b"..".to_vec(),
+            },
+        ];
+
+        self.children(ino)?;
+
+        for (name, child) in &self.children[&ino] {
+            let mode = match &self.inodes[*child as usize - 1].entry {
+                Entry::Virtual { .. } => libc::S_IFDIR,
+                Entry::Node(node) => node.mode,
+            };
+
+            entries.push(DirEntry {
+                ino: *child,
+                mode,
+                name: name.clone(),
+            });
+        }
+
+        Ok(entries)
+    }
+}
+
+/// The directory a snapshot is shown as, with the metadata of the directory
+/// backed up if the snapshot has it.
+fn snapshot_dir(name: &str, snapshot: &Snapshot) -> Node {
+    if let Some(root) = &snapshot.root {
+        return Node {
+            name: USeg::from_segment_bytes(name.as_bytes()),
+            kind: NodeKind::Dir {
+                subtree: snapshot.tree,
+            },
+            ..root.clone()
+        };
+    }
+
+    Node {
+        name: USeg::from_segment_bytes(name.as_bytes()),
+        mode: libc::S_IFDIR | 0o555,
+        mtime: snapshot.time,
+        atime: snapshot.time,
+        ctime: snapshot.time,
+        uid: snapshot.uid,
+        gid: snapshot.gid,
+        user: snapshot.username.clone(),
+        inode: 0,
+        kind: NodeKind::Dir {
+            subtree: snapshot.tree,
+        },
+    }
+}
+
+/// Finds the blob containing a byte offset, given the start of every blob.
+fn blob_at(offsets: &[u64], offset: u64) -> usize {
+    offsets.partition_point(|&start| start <= offset) - 1
+}
+
+/// Mounts every snapshot of a repository read-only below `mountpoint`, both
+/// as `snapshots/<time>` and as `hosts/<host>/<time>`, until it is unmounted.
+pub fn run(repository: &Repository, mountpoint: &Path, allow_other: bool) {
+    let mut fs = SnapshotFs {
+        repository,
+        inodes: Vec::new(),
+        children: HashMap::new(),
+        handles: HashMap::new(),
+        next_handle: 0,
+        blobs: BlobCache {
+            blobs: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+        },
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+    };
+
+    let snapshots = repository.snapshots();
+    let latest = snapshots.last().map_or(0, |(_, snapshot)| snapshot.time);
+    let mut names = HashMap::new();
+
+    for (_, snapshot) in &snapshots {
+        *names.entry(snapshot.time).or_insert(0) += 1;
+    }
+
+    let root = fs.allocate(ROOT_ID, Entry::Virtual { mtime: latest });
+    let all = fs.allocate(root, Entry::Virtual { mtime: latest });
+    let hosts = fs.allocate(root, Entry::Virtual { mtime: latest });
+    let mut by_time = Vec::new();
+    let mut by_host = BTreeMap::<String, Vec<_>>::new();
+
+    for (id, snapshot) in &snapshots {
+        // Snapshots taken within the same second are told apart by their id.
+        let mut name = Timestamp::from_second(snapshot.time).unwrap().to_string();
+        if names[&snapshot.time] > 1 {
+            name = format!("{}-{}", name, &id.to_hex()[..8]);
+        }
+
+        let dir = snapshot_dir(&name, snapshot);
+        by_host
+            .entry(snapshot.hostname.clone())
+            .or_default()
+            .push(dir.clone());
+        by_time.push(dir);
+    }
+
+    let mut listing = Vec::new();
+    for node in by_time {
+        let name = node.name.as_bytes().to_vec();
+        listing.push((name, fs.allocate(all, Entry::Node(node))));
+    }
+
+    fs.children.insert(all, listing);
+
+    let mut host_listing = Vec::new();
+    for (host, dirs) in by_host {
+        let mtime = dirs.last().unwrap().mtime;
+        let host_ino = fs.allocate(hosts, Entry::Virtual { mtime });
+        let mut listing = Vec::new();
+
+        for node in dirs {
+            let name = node.name.as_bytes().to_vec();
+            listing.push((name, fs.allocate(host_ino, Entry::Node(node))));
+        }
+
+        fs.children.insert(host_ino, listing);
+        host_listing.push((host.into_bytes(), host_ino));
+    }
+
+    fs.children.insert(hosts, host_listing);
+    fs.children.insert(
+        root,
+        vec![(b"snapshots".to_vec(), all), (b"hosts".to_vec(), hosts)],
+    );
+
+    println!(
+        "serving {} snapshots at {:?} until unmounted",
+        snapshots.len(),
+        mountpoint
+    );
+
+    fuse::mount(mountpoint, allow_other, &mut fs);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileExt;
+    use std::time::{Duration, Instant};
+    use std::{fs, thread};
+
+    use super::*;
+    use crate::cmd::{self, backup};
+    use crate::pack::Compression;
+    use crate::repo::{ChunkerParams, Cipher, Key, PackSize};
+
+    #[test]
+    fn test_blob_at() {
+        let offsets = [0, 10, 25, 40];
+        assert_eq!(blob_at(&offsets, 0), 0);
+        assert_eq!(blob_at(&offsets, 9), 0);
+        assert_eq!(blob_at(&offsets, 10), 1);
+        assert_eq!(blob_at(&offsets, 39), 2);
+    }
+
+    #[test]
+    #[ignore = "needs /dev/fuse and either root or fusermount"]
+    fn test_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, root) = (dir.path().join("source"), dir.path().join("repo"));
+        let mountpoint = dir.path().join("mnt");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::create_dir(&mountpoint).unwrap();
+        let mut big = vec![0; 8 * 1024 * 1024];
+        blake3::Hasher::new().finalize_xof().fill(&mut big);
+        fs::write(source.join("big"), &big).unwrap();
+        fs::write(source.join("sub/small"), b"small file").unwrap();
+        std::os::unix::fs::symlink("sub/small", source.join("link")).unwrap();
+
+        let key = Key::generate();
+        let repository = Repository::init(
+            &root,
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+        let options = backup::Options {
+            compression: Compression::Auto,
+            inline_size: 128,
+            deterministic: false,
+        };
+        backup::run(&repository, &source, options);
+
+        let (_, snapshot) = repository.snapshots().pop().unwrap();
+        let NodeKind::File { content, .. } = cmd::lookup(&repository, snapshot.tree, "big").kind
+        else {
+            panic!("not a file");
+        };
+        assert!(content.len() > 1);
+        let boundary = repository.locate(&content[0]).unwrap().size() as u64;
+
+        let server = {
+            let mountpoint = mountpoint.clone();
+            thread::spawn(move || run(&Repository::open(&root, key), &mountpoint, false))
+        };
+
+        let started = Instant::now();
+        while !mountpoint.join("snapshots").exists() {
+            if server.is_finished() {
+                panic!("mounting failed: {:?}", server.join().err());
+            }
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let names = |path: &Path| {
+            let mut names = fs::read_dir(path)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(names(&mountpoint), ["hosts", "snapshots"]);
+        let snapshots = names(&mountpoint.join("snapshots"));
+        assert_eq!(snapshots.len(), 1);
+        let tree = mountpoint.join("snapshots").join(&snapshots[0]);
+        assert_eq!(names(&tree), ["big", "link", "sub"]);
+        assert_eq!(names(&tree.join("sub")), ["small"]);
+
+        assert_eq!(fs::read(tree.join("big")).unwrap(), big);
+        let mut straddling = [0; 4096];
+        fs::File::open(tree.join("big"))
+            .unwrap()
+            .read_exact_at(&mut straddling, boundary - 1000)
+            .unwrap();
+        let start = boundary as usize - 1000;
+        assert_eq!(straddling, big[start..start + 4096]);
+
+        assert_eq!(fs::read(tree.join("sub/small")).unwrap(), b"small file");
+        assert_eq!(
+            fs::read_link(tree.join("link")).unwrap(),
+            Path::new("sub/small")
+        );
+
+        if unsafe { libc::geteuid() } == 0 {
+            let target = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+            assert_eq!(
+                unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) },
+                0
+            );
+        } else {
+            let status = std::process::Command::new("fusermount3")
+                .args(["-u", "-z"])
+                .arg(&mountpoint)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        server.join().unwrap();
+    }
+}
@@ -32,6 +32,8 @@
 use std::fmt;
 use std::io::Read;
 
+use crate::chunker::{Chunker, FastCdc2020};
+
 /// Smallest acceptable value for the minimum chunk size.
 pub const MINIMUM_MIN: u32 = 64;
 /// Largest acceptable value for the minimum chunk size.
@@ -231,7 +233,76 @@ const GEAR_LS: [u64; 256] = [
     0x1c7c8443a6c28826, 0xde29a1b0d7e34458, 0xc3b061a7e2d8bbb6, 0x557a56548a2a09c2
 ];
 
-// Find the next chunk cut point in the source.
+///
+/// The pair of gear tables used to find cut points.
+///
+/// The canonical tables can be permuted with a secret seed, so that the chunk
+/// boundaries of known content can't be predicted by anyone without the seed.
+///
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Gear {
+    table: [u64; 256],
+    table_ls: [u64; 256],
+}
+
+impl Gear {
+    pub const CANONICAL: Gear = Gear {
+        table: GEAR,
+        table_ls: GEAR_LS,
+    };
+
+    ///
+    /// Shuffles the canonical tables with a Fisher-Yates shuffle driven by a
+    /// keystream derived from `seed`.
+    ///
+    pub fn permuted(seed: &[u8; 32]) -> Self {
+        let mut table = GEAR;
+        let mut stream = blake3::Hasher::new_keyed(seed)
+            .update(b"gear permutation")
+            .finalize_xof();
+
+        for i in (1..table.len()).rev() {
+            let mut bytes = [0; 8];
+            stream.fill(&mut bytes);
+            let j = (u64::from_le_bytes(bytes) % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+
+        Gear {
+            table,
+            table_ls: table.map(|value| value << 1),
+        }
+    }
+
+    ///
+    /// The table each byte is hashed with, which the 2016 version adds to the
+    /// hash one byte at a time.
+    ///
+    pub fn table(&self) -> &[u64; 256] {
+        &self.table
+    }
+}
+
+//
+// Number of bytes the gear hash depends on. Each byte is shifted one bit
+// further left by every byte after it, so after 64 bytes it has left the hash
+// entirely and the hash at any position is a function of the 64 bytes up to
+// it. This is what lets the accelerated paths hash several segments of the
+// source at once, each lane starting 64 bytes ahead of its segment.
+//
+const WINDOW: usize = 64;
+
+//
+// Number of bytes each lane hashes per block of the accelerated paths, after
+// its warmup over the window. Must be a multiple of eight, as lanes read eight
+// bytes at a time, which also gives every lane the same alternation of even
+// and odd positions.
+//
+const LANE_LENGTH: usize = 1024;
+
+// Find the next chunk cut point in the source, using the widest SIMD
+// instructions the CPU supports. Produces the same cut points and hashes as
+// [`cut_scalar`].
 #[allow(clippy::too_many_arguments)]
 pub fn cut(
     source: &[u8],
@@ -242,6 +313,384 @@ pub fn cut(
     mask_l: u64,
     mask_s_ls: u64,
     mask_l_ls: u64,
+    gear: &Gear,
+) -> (u64, usize) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let masks = (mask_s, mask_l, mask_s_ls, mask_l_ls);
+        if is_x86_feature_detected!("avx2") {
+            return cut_lanes(
+                source,
+                min_size,
+                avg_size,
+                max_size,
+                masks,
+                gear,
+                &x86::AVX2,
+            );
+        }
+        if is_x86_feature_detected!("sse4.1") {
+            return cut_lanes(
+                source,
+                min_size,
+                avg_size,
+                max_size,
+                masks,
+                gear,
+                &x86::SSE41,
+            );
+        }
+    }
+
+    cut_scalar(
+        source, min_size, avg_size, max_size, mask_s, mask_l, mask_s_ls, mask_l_ls, gear,
+    )
+}
+
+//
+// A SIMD implementation of the gear hash, which hashes `lanes` segments of
+// `LANE_LENGTH` bytes side by side.
+//
+struct Lanes {
+    lanes: usize,
+    // Finds the first cut point in the `lanes` segments starting at `start`,
+    // with the hash and position `scan_scalar` would return for it. The caller
+    // must check that the CPU supports the instructions, that `start` is even
+    // and at least `WINDOW`, and that all segments are within the source.
+    scan: Scan,
+}
+
+type Scan = unsafe fn(&[u8], &Gear, usize, u64, u64) -> Option<(u64, usize)>;
+
+// Splits the source the way `cut_scalar` does, into the positions checked
+// against the small masks and those checked against the large ones, and scans
+// both with the given lanes.
+fn cut_lanes(
+    source: &[u8],
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    (mask_s, mask_l, mask_s_ls, mask_l_ls): (u64, u64, u64, u64),
+    gear: &Gear,
+    lanes: &Lanes,
+) -> (u64, usize) {
+    let mut remaining = source.len();
+    if remaining <= min_size {
+        return (0, remaining);
+    }
+    let mut center = avg_size;
+    if remaining > max_size {
+        remaining = max_size;
+    } else if remaining < center {
+        center = remaining;
+    }
+
+    // Hashing starts afresh at `origin` and checks positions in pairs, so the
+    // bounds are rounded down to even positions.
+    let origin = min_size / 2 * 2;
+    let center = (center / 2 * 2).max(origin);
+    let end = (remaining / 2 * 2).max(center);
+
+    let scans = [
+        (origin, center, mask_s, mask_s_ls),
+        (center, end, mask_l, mask_l_ls),
+    ];
+    for (from, to, mask, mask_ls) in scans {
+        if let Some(found) = scan(source, gear, origin, from, to, mask, mask_ls, lanes) {
+            return found;
+        }
+    }
+
+    // If all else fails, return the largest chunk. This will happen with
+    // pathological data, such as all zeroes.
+    let hash = if end > origin {
+        hash_until(source, gear, origin, end)
+    } else {
+        0
+    };
+    (hash, remaining)
+}
+
+// Finds the first cut point in `from..to`, hashing from `origin`, in blocks of
+// lanes where there is room for them and with `scan_scalar` elsewhere.
+#[allow(clippy::too_many_arguments)]
+fn scan(
+    source: &[u8],
+    gear: &Gear,
+    origin: usize,
+    from: usize,
+    to: usize,
+    mask: u64,
+    mask_ls: u64,
+    lanes: &Lanes,
+) -> Option<(u64, usize)> {
+    // Lanes need a full window of source after `origin` to warm up on.
+    let mut start = from.max(origin + WINDOW).min(to);
+    if let Some(found) = scan_scalar(source, gear, origin, from, start, mask, mask_ls) {
+        return Some(found);
+    }
+
+    let block = lanes.lanes * LANE_LENGTH;
+    while to - start >= block {
+        // Safety: `start` is even, at least `WINDOW` and the block ends within
+        // `to`, and `cut` only passes lanes the CPU supports.
+        if let Some(found) = unsafe { (lanes.scan)(source, gear, start, mask, mask_ls) } {
+            return Some(found);
+        }
+        start += block;
+    }
+
+    scan_scalar(source, gear, origin, start, to, mask, mask_ls)
+}
+
+// Finds the first cut point in `from..to` one byte at a time, hashing from
+// `origin` or a window ahead of `from`, whichever is later.
+fn scan_scalar(
+    source: &[u8],
+    gear: &Gear,
+    origin: usize,
+    from: usize,
+    to: usize,
+    mask: u64,
+    mask_ls: u64,
+) -> Option<(u64, usize)> {
+    let mut hash = if from > origin {
+        hash_until(source, gear, origin, from)
+    } else {
+        0
+    };
+
+    for (position, &byte) in source.iter().enumerate().take(to).skip(from) {
+        hash = (hash << 1).wrapping_add(gear.table[byte as usize]);
+        // Even positions are checked the way `cut_scalar` checks the first of
+        // each pair, which has one bit less of the hash to go on.
+        if position % 2 == 0 {
+            if ((hash << 1) & mask_ls) == 0 {
+                return Some((hash << 1, position));
+            }
+        } else if (hash & mask) == 0 {
+            return Some((hash, position));
+        }
+    }
+
+    None
+}
+
+// The hash as of the byte before `end`, hashing from `origin`.
+fn hash_until(source: &[u8], gear: &Gear, origin: usize, end: usize) -> u64 {
+    let start = end.saturating_sub(WINDOW).max(origin);
+    source[start..end].iter().fold(0, |hash: u64, &byte| {
+        (hash << 1).wrapping_add(gear.table[byte as usize])
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+    use std::mem;
+
+    use super::{Gear, LANE_LENGTH, Lanes, WINDOW};
+
+    pub const AVX2: Lanes = Lanes {
+        lanes: 4,
+        scan: scan_avx2,
+    };
+
+    pub const SSE41: Lanes = Lanes {
+        lanes: 2,
+        scan: scan_sse41,
+    };
+
+    // Where each lane starts, asserting that all of them and their warmups
+    // are within the source, which the unchecked reads below rely on.
+    fn segments<const N: usize>(source: &[u8], start: usize) -> [usize; N] {
+        assert!(start >= WINDOW && start % 2 == 0);
+        assert!(start + N * LANE_LENGTH <= source.len());
+        std::array::from_fn(|lane| start + lane * LANE_LENGTH)
+    }
+
+    // Reads the eight bytes of each lane starting `offset` bytes into its
+    // warmup, as little endian words with the first byte lowest.
+    fn words<const N: usize>(source: &[u8], segments: [usize; N], offset: usize) -> [u64; N] {
+        segments.map(|segment| {
+            let at = segment + offset - WINDOW;
+            // Safety: `segments` checked the lanes are within the source.
+            unsafe { source.as_ptr().add(at).cast::<u64>().read_unaligned() }
+        })
+    }
+
+    // Records the first cut point of each lane at a pair of positions, where
+    // `even` and `odd` have a bit set for each lane which has one there.
+    fn record<const N: usize>(
+        found: &mut [Option<(u64, usize)>; N],
+        positions: [usize; N],
+        (even, hashes_even): (i32, [u64; N]),
+        (odd, hashes_odd): (i32, [u64; N]),
+    ) {
+        for lane in 0..N {
+            if found[lane].is_some() {
+                continue;
+            }
+            if even & (1 << lane) != 0 {
+                found[lane] = Some((hashes_even[lane], positions[lane]));
+            } else if odd & (1 << lane) != 0 {
+                found[lane] = Some((hashes_odd[lane], positions[lane] + 1));
+            }
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn scan_avx2(
+        source: &[u8],
+        gear: &Gear,
+        start: usize,
+        mask: u64,
+        mask_ls: u64,
+    ) -> Option<(u64, usize)> {
+        let segments = segments::<4>(source, start);
+        // Looks up the lowest byte of each lane in the gear table with a
+        // single gather, then moves on to the next byte.
+        let low_byte = _mm256_set1_epi64x(0xff);
+        let table = gear.table.as_ptr().cast::<i64>();
+        let gear_of = |bytes: &mut __m256i| {
+            let index = _mm256_and_si256(*bytes, low_byte);
+            *bytes = _mm256_srli_epi64::<8>(*bytes);
+            // Safety: the indices are bytes, within the 256 entry table.
+            unsafe { _mm256_i64gather_epi64::<8>(table, index) }
+        };
+        // Safety: an array of four words is a vector of them.
+        let words = |offset| unsafe {
+            mem::transmute::<[u64; 4], __m256i>(words(source, segments, offset))
+        };
+
+        let mask = _mm256_set1_epi64x(mask as i64);
+        let mask_ls = _mm256_set1_epi64x(mask_ls as i64);
+        let zero = _mm256_setzero_si256();
+        let mut hash = zero;
+
+        for offset in (0..WINDOW).step_by(8) {
+            let mut bytes = words(offset);
+            for _ in 0..8 {
+                hash = _mm256_add_epi64(_mm256_slli_epi64::<1>(hash), gear_of(&mut bytes));
+            }
+        }
+
+        let mut found = [None; 4];
+        for offset in (WINDOW..WINDOW + LANE_LENGTH).step_by(8) {
+            let mut bytes = words(offset);
+            for pair in (0..8).step_by(2) {
+                hash = _mm256_add_epi64(_mm256_slli_epi64::<1>(hash), gear_of(&mut bytes));
+                let hash_ls = _mm256_slli_epi64::<1>(hash);
+                let even = _mm256_cmpeq_epi64(_mm256_and_si256(hash_ls, mask_ls), zero);
+                let even = _mm256_movemask_pd(_mm256_castsi256_pd(even));
+
+                hash = _mm256_add_epi64(hash_ls, gear_of(&mut bytes));
+                let odd = _mm256_cmpeq_epi64(_mm256_and_si256(hash, mask), zero);
+                let odd = _mm256_movemask_pd(_mm256_castsi256_pd(odd));
+
+                if (even | odd) != 0 {
+                    let positions = segments.map(|segment| segment + offset + pair - WINDOW);
+                    // Safety: a vector of four words is an array of them.
+                    let hashes = unsafe {
+                        (
+                            mem::transmute::<__m256i, [u64; 4]>(hash_ls),
+                            mem::transmute::<__m256i, [u64; 4]>(hash),
+                        )
+                    };
+                    record(&mut found, positions, (even, hashes.0), (odd, hashes.1));
+
+                    // Nothing later can come before a cut point in the first
+                    // lane.
+                    if found[0].is_some() {
+                        return found[0];
+                    }
+                }
+            }
+        }
+
+        found.into_iter().flatten().next()
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn scan_sse41(
+        source: &[u8],
+        gear: &Gear,
+        start: usize,
+        mask: u64,
+        mask_ls: u64,
+    ) -> Option<(u64, usize)> {
+        let segments = segments::<2>(source, start);
+        // Looks up the lowest byte of each lane in the gear table, there
+        // being no gather before AVX2.
+        let gear_of = |bytes: [u64; 2]| {
+            let [a, b] = bytes.map(|word| gear.table[word as u8 as usize] as i64);
+            _mm_set_epi64x(b, a)
+        };
+
+        let mask = _mm_set1_epi64x(mask as i64);
+        let mask_ls = _mm_set1_epi64x(mask_ls as i64);
+        let zero = _mm_setzero_si128();
+        let mut hash = zero;
+
+        for offset in (0..WINDOW).step_by(8) {
+            let mut bytes = words(source, segments, offset);
+            for _ in 0..8 {
+                hash = _mm_add_epi64(_mm_slli_epi64::<1>(hash), gear_of(bytes));
+                bytes = bytes.map(|word| word >> 8);
+            }
+        }
+
+        let mut found = [None; 2];
+        for offset in (WINDOW..WINDOW + LANE_LENGTH).step_by(8) {
+            let mut bytes = words(source, segments, offset);
+            for pair in (0..8).step_by(2) {
+                hash = _mm_add_epi64(_mm_slli_epi64::<1>(hash), gear_of(bytes));
+                bytes = bytes.map(|word| word >> 8);
+                let hash_ls = _mm_slli_epi64::<1>(hash);
+                let even = _mm_cmpeq_epi64(_mm_and_si128(hash_ls, mask_ls), zero);
+                let even = _mm_movemask_pd(_mm_castsi128_pd(even));
+
+                hash = _mm_add_epi64(hash_ls, gear_of(bytes));
+                bytes = bytes.map(|word| word >> 8);
+                let odd = _mm_cmpeq_epi64(_mm_and_si128(hash, mask), zero);
+                let odd = _mm_movemask_pd(_mm_castsi128_pd(odd));
+
+                if (even | odd) != 0 {
+                    let positions = segments.map(|segment| segment + offset + pair - WINDOW);
+                    // Safety: a vector of two words is an array of them.
+                    let hashes = unsafe {
+                        (
+                            mem::transmute::<__m128i, [u64; 2]>(hash_ls),
+                            mem::transmute::<__m128i, [u64; 2]>(hash),
+                        )
+                    };
+                    record(&mut found, positions, (even, hashes.0), (odd, hashes.1));
+
+                    if found[0].is_some() {
+                        return found[0];
+                    }
+                }
+            }
+        }
+
+        found.into_iter().flatten().next()
+    }
+}
+
+// Find the next chunk cut point in the source, two bytes at a time. This is
+// the reference the accelerated paths must match, and the fallback on CPUs
+// without them.
+#[allow(clippy::too_many_arguments)]
+pub fn cut_scalar(
+    source: &[u8],
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    mask_s: u64,
+    mask_l: u64,
+    mask_s_ls: u64,
+    mask_l_ls: u64,
+    gear: &Gear,
 ) -> (u64, usize) {
     let mut remaining = source.len();
     if remaining <= min_size {
@@ -257,11 +706,11 @@ pub fn cut(
     let mut hash: u64 = 0;
     while index < center / 2 {
         let a = index * 2;
-        hash = (hash << 2).wrapping_add(GEAR_LS[source[a] as usize]);
+        hash = (hash << 2).wrapping_add(gear.table_ls[source[a] as usize]);
         if (hash & mask_s_ls) == 0 {
             return (hash, a);
         }
-        hash = hash.wrapping_add(GEAR[source[a + 1] as usize]);
+        hash = hash.wrapping_add(gear.table[source[a + 1] as usize]);
         if (hash & mask_s) == 0 {
             return (hash, a + 1);
         }
@@ -269,11 +718,11 @@ pub fn cut(
     }
     while index < remaining / 2 {
         let a = index * 2;
-        hash = (hash << 2).wrapping_add(GEAR_LS[source[a] as usize]);
+        hash = (hash << 2).wrapping_add(gear.table_ls[source[a] as usize]);
         if (hash & mask_l_ls) == 0 {
             return (hash, a);
         }
-        hash = hash.wrapping_add(GEAR[source[a + 1] as usize]);
+        hash = hash.wrapping_add(gear.table[source[a + 1] as usize]);
         if (hash & mask_l) == 0 {
             return (hash, a + 1);
         }
@@ -312,7 +761,7 @@ pub enum Normalization {
 }
 
 impl Normalization {
-    pub(self) fn bits(&self) -> u32 {
+    pub(crate) fn bits(&self) -> u32 {
         match self {
             Normalization::Level0 => 0,
             Normalization::Level1 => 1,
@@ -357,7 +806,7 @@ pub struct Chunk {
 ///
 /// ```no_run
 /// use std::fs;
-/// use crate::fastcdc;
+/// use casb::fastcdc;
 /// let contents = fs::read("test/fixtures/SekienAkashita.jpg").unwrap();
 /// let chunker = fastcdc::FastCDC::new(&contents, 8192, 16384, 65535);
 /// for entry in chunker {
@@ -377,6 +826,7 @@ pub struct FastCDC<'a> {
     mask_l: u64,
     mask_s_ls: u64,
     mask_l_ls: u64,
+    gear: Gear,
 }
 
 impl<'a> FastCDC<'a> {
@@ -420,9 +870,18 @@ impl<'a> FastCDC<'a> {
             mask_l,
             mask_s_ls: mask_s << 1,
             mask_l_ls: mask_l << 1,
+            gear: Gear::CANONICAL,
         }
     }
 
+    ///
+    /// Use the given gear tables rather than the canonical ones.
+    ///
+    pub fn with_gear(mut self, gear: Gear) -> Self {
+        self.gear = gear;
+        self
+    }
+
     ///
     /// Find the next cut point in the data, where `start` is the position from
     /// which to start processing the source data, and `remaining` are the
@@ -447,6 +906,7 @@ impl<'a> FastCDC<'a> {
             self.mask_l,
             self.mask_s_ls,
             self.mask_l_ls,
+            &self.gear,
         );
         (hash, start + count)
     }
@@ -538,18 +998,37 @@ pub struct ChunkData {
     pub data: Vec<u8>,
 }
 
+///
+/// Represents a chunk returned from [`StreamCDC::next_chunk`], borrowed from
+/// the buffer of the chunker until the next chunk is read.
+///
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ChunkRef<'a> {
+    /// The gear hash value as of the end of the chunk.
+    pub hash: u64,
+    /// Starting byte position within the source.
+    pub offset: u64,
+    /// Source bytes contained in this chunk.
+    pub data: &'a [u8],
+}
+
 ///
 /// The FastCDC chunker implementation from 2020 with streaming support.
 ///
 /// Use `new` to construct an instance, and then iterate over the [`ChunkData`]s
-/// via the [`Iterator`] trait.
+/// via the [`Iterator`] trait, or borrow each chunk in turn with
+/// [`next_chunk`](StreamCDC::next_chunk) to avoid copying it. Other algorithms
+/// can find the cut points instead, see [`with_chunker`](StreamCDC::with_chunker).
 ///
-/// Note that this struct allocates a [`Vec<u8>`] of `max_size` bytes to act as a
-/// buffer when reading from the source and finding chunk boundaries.
+/// Note that this struct buffers up to `max_size` bytes when reading from the
+/// source and finding chunk boundaries. The buffer grows as data is read, so
+/// small sources need little memory, and it can be handed from one chunker to
+/// the next with [`with_buffer`](StreamCDC::with_buffer) and
+/// [`into_buffer`](StreamCDC::into_buffer).
 ///
 /// ```no_run
 /// # use std::fs::File;
-/// # use crate::fastcdc::StreamCDC;
+/// # use casb::fastcdc::StreamCDC;
 /// let source = File::open("test/fixtures/SekienAkashita.jpg").unwrap();
 /// let chunker = StreamCDC::new(source, 4096, 16384, 65535);
 /// for result in chunker {
@@ -558,26 +1037,22 @@ pub struct ChunkData {
 /// }
 /// ```
 ///
-pub struct StreamCDC<R: Read> {
+pub struct StreamCDC<R: Read, C: Chunker = FastCdc2020> {
     /// Buffer of data from source for finding cut points.
     buffer: Vec<u8>,
-    /// Maximum capacity of the buffer (always `max_size`).
+    /// Maximum length of the buffer (always `max_size`).
     capacity: usize,
-    /// Number of relevant bytes in the `buffer`.
-    length: usize,
+    /// Number of bytes at the start of `buffer` taken by the last chunk, which
+    /// are dropped before the next one is read.
+    consumed: usize,
     /// Source from which data is read into `buffer`.
     source: R,
     /// Number of bytes read from the source so far.
     processed: u64,
     /// True when the source produces no more data.
     eof: bool,
-    min_size: usize,
-    avg_size: usize,
-    max_size: usize,
-    mask_s: u64,
-    mask_l: u64,
-    mask_s_ls: u64,
-    mask_l_ls: u64,
+    /// Finds the cut points in `buffer`.
+    chunker: C,
 }
 
 impl<R: Read> StreamCDC<R> {
@@ -600,115 +1075,121 @@ impl<R: Read> StreamCDC<R> {
         max_size: u32,
         level: Normalization,
     ) -> Self {
-        assert!(min_size >= MINIMUM_MIN);
-        assert!(min_size <= MINIMUM_MAX);
-        assert!(avg_size >= AVERAGE_MIN);
-        assert!(avg_size <= AVERAGE_MAX);
-        assert!(max_size >= MAXIMUM_MIN);
-        assert!(max_size <= MAXIMUM_MAX);
-        let bits = logarithm2(avg_size);
-        let normalization = level.bits();
-        let mask_s = MASKS[(bits + normalization) as usize];
-        let mask_l = MASKS[(bits - normalization) as usize];
+        let chunker = FastCdc2020::new(min_size, avg_size, max_size, level);
+        StreamCDC::with_chunker(source, chunker)
+    }
+
+    ///
+    /// Use the given gear tables rather than the canonical ones.
+    ///
+    pub fn with_gear(mut self, gear: Gear) -> Self {
+        self.chunker = self.chunker.with_gear(gear);
+        self
+    }
+}
+
+impl<R: Read, C: Chunker> StreamCDC<R, C> {
+    ///
+    /// Construct a [`StreamCDC`] that finds cut points with the given chunker
+    /// rather than the 2020 FastCDC.
+    ///
+    pub fn with_chunker(source: R, chunker: C) -> Self {
         Self {
-            buffer: vec![0_u8; max_size as usize],
-            capacity: max_size as usize,
-            length: 0,
+            buffer: Vec::new(),
+            capacity: chunker.max_size(),
+            consumed: 0,
             source,
             eof: false,
             processed: 0,
-            min_size: min_size as usize,
-            avg_size: avg_size as usize,
-            max_size: max_size as usize,
-            mask_s,
-            mask_l,
-            mask_s_ls: mask_s << 1,
-            mask_l_ls: mask_l << 1,
+            chunker,
         }
     }
 
+    ///
+    /// Reuse the given buffer, such as one returned by `into_buffer`, rather
+    /// than allocating a new one.
+    ///
+    pub fn with_buffer(mut self, mut buffer: Vec<u8>) -> Self {
+        buffer.clear();
+        self.buffer = buffer;
+        self
+    }
+
+    ///
+    /// Give up the buffer so the next chunker can reuse it.
+    ///
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.buffer
+    }
+
     /// Fill the buffer with data from the source, returning the number of bytes
     /// read (zero if end of source has been reached).
     fn fill_buffer(&mut self) -> Result<usize, Error> {
-        // this code originally copied from asuran crate
         if self.eof {
             Ok(0)
         } else {
-            let mut all_bytes_read = 0;
-            while !self.eof && self.length < self.capacity {
-                let bytes_read = self.source.read(&mut self.buffer[self.length..])?;
-                if bytes_read == 0 {
-                    self.eof = true;
-                } else {
-                    self.length += bytes_read;
-                    all_bytes_read += bytes_read;
-                }
+            let wanted = self.capacity - self.buffer.len();
+            let bytes_read = (&mut self.source)
+                .take(wanted as u64)
+                .read_to_end(&mut self.buffer)?;
+            if bytes_read < wanted {
+                self.eof = true;
             }
-            Ok(all_bytes_read)
+            Ok(bytes_read)
         }
     }
 
-    /// Drains a specified number of bytes from the buffer, then resizes the
-    /// buffer back to `capacity` size in preparation for further reads.
-    fn drain_bytes(&mut self, count: usize) -> Result<Vec<u8>, Error> {
-        // this code originally copied from asuran crate
-        if count > self.length {
-            Err(Error::Other(format!(
-                "drain_bytes() called with count larger than length: {} > {}",
-                count, self.length
-            )))
-        } else {
-            let data = self.buffer.drain(..count).collect::<Vec<u8>>();
-            self.length -= count;
-            self.buffer.resize(self.capacity, 0_u8);
-            Ok(data)
+    ///
+    /// Find the next chunk in the source and borrow it from the buffer, or
+    /// return `None` once the end of the source has been reached.
+    ///
+    pub fn next_chunk(&mut self) -> Option<Result<ChunkRef<'_>, Error>> {
+        match self.read_chunk() {
+            Err(Error::Empty) => None,
+            Err(error) => Some(Err(error)),
+            Ok((hash, offset)) => Some(Ok(ChunkRef {
+                hash,
+                offset,
+                data: &self.buffer[..self.consumed],
+            })),
         }
     }
 
-    /// Find the next chunk in the source. If the end of the source has been
-    /// reached, returns `Error::Empty` as the error.
-    fn read_chunk(&mut self) -> Result<ChunkData, Error> {
+    /// Find the next chunk in the source, which takes the first `consumed`
+    /// bytes of the buffer, and return its hash and offset. If the end of the
+    /// source has been reached, returns `Error::Empty` as the error.
+    fn read_chunk(&mut self) -> Result<(u64, u64), Error> {
+        self.buffer.drain(..self.consumed);
+        self.consumed = 0;
+
         self.fill_buffer()?;
-        if self.length == 0 {
+        if self.buffer.is_empty() {
             Err(Error::Empty)
         } else {
-            let (hash, count) = cut(
-                &self.buffer[..self.length],
-                self.min_size,
-                self.avg_size,
-                self.max_size,
-                self.mask_s,
-                self.mask_l,
-                self.mask_s_ls,
-                self.mask_l_ls,
-            );
+            let (hash, count) = self.chunker.cut(&self.buffer);
             if count == 0 {
                 Err(Error::Empty)
             } else {
                 let offset = self.processed;
                 self.processed += count as u64;
-                let data = self.drain_bytes(count)?;
-                Ok(ChunkData {
-                    hash,
-                    offset,
-                    length: count,
-                    data,
-                })
+                self.consumed = count;
+                Ok((hash, offset))
             }
         }
     }
 }
 
-impl<R: Read> Iterator for StreamCDC<R> {
+impl<R: Read, C: Chunker> Iterator for StreamCDC<R, C> {
     type Item = Result<ChunkData, Error>;
 
     fn next(&mut self) -> Option<Result<ChunkData, Error>> {
-        let slice = self.read_chunk();
-        if let Err(Error::Empty) = slice {
-            None
-        } else {
-            Some(slice)
-        }
+        let chunk = self.next_chunk()?;
+        Some(chunk.map(|chunk| ChunkData {
+            hash: chunk.hash,
+            offset: chunk.offset,
+            length: chunk.data.len(),
+            data: chunk.data.to_vec(),
+        }))
     }
 }
 
@@ -1075,4 +1556,158 @@ mod tests {
         }
         assert_eq!(index, 5);
     }
+
+    #[test]
+    fn test_permuted_gear() {
+        let gear = Gear::permuted(&[7; 32]);
+        assert_eq!(gear, Gear::permuted(&[7; 32]));
+        assert_ne!(gear, Gear::permuted(&[8; 32]));
+        assert_ne!(gear, Gear::CANONICAL);
+
+        let mut sorted = gear.table;
+        let mut canonical = GEAR;
+        sorted.sort();
+        canonical.sort();
+        assert_eq!(sorted, canonical);
+
+        let mut source = vec![0; 65536];
+        blake3::Hasher::new().finalize_xof().fill(&mut source);
+        let cuts = |gear: Gear| {
+            FastCDC::new(&source, 64, 256, 1024)
+                .with_gear(gear)
+                .map(|chunk| chunk.offset)
+                .collect::<Vec<_>>()
+        };
+
+        assert_ne!(cuts(gear), cuts(Gear::CANONICAL));
+    }
+
+    #[test]
+    fn test_cut_matches_scalar() {
+        // A xorshift generator, so failures can be reproduced.
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut next = |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+
+        #[allow(unused_mut)]
+        let mut all_lanes: Vec<&Lanes> = Vec::new();
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                all_lanes.push(&x86::AVX2);
+            }
+            if is_x86_feature_detected!("sse4.1") {
+                all_lanes.push(&x86::SSE41);
+            }
+        }
+
+        for case in 0..500u32 {
+            let min_size = 64 + next(4096);
+            let avg_size = min_size + next(16384);
+            let max_size = avg_size + next(65536);
+
+            // Masks with few bits find cut points everywhere, masks with many
+            // rarely find any.
+            let mut masks = [0u64; 2];
+            for mask in &mut masks {
+                for _ in 0..1 + next(24) {
+                    *mask |= 1 << next(64);
+                }
+            }
+            let [mask_s, mask_l] = masks;
+
+            let gear = if next(2) == 0 {
+                Gear::CANONICAL
+            } else {
+                Gear::permuted(&[next(256) as u8; 32])
+            };
+
+            let mut source = vec![0; next(max_size * 2)];
+            match next(3) {
+                0 => blake3::Hasher::new()
+                    .update(&case.to_le_bytes())
+                    .finalize_xof()
+                    .fill(&mut source),
+                1 => {
+                    let alphabet = 1 + next(4);
+                    source
+                        .iter_mut()
+                        .for_each(|byte| *byte = next(alphabet) as u8);
+                }
+                _ => {}
+            }
+
+            let expected = cut_scalar(
+                &source,
+                min_size,
+                avg_size,
+                max_size,
+                mask_s,
+                mask_l,
+                mask_s << 1,
+                mask_l << 1,
+                &gear,
+            );
+            let masks = (mask_s, mask_l, mask_s << 1, mask_l << 1);
+
+            for lanes in &all_lanes {
+                let actual = cut_lanes(&source, min_size, avg_size, max_size, masks, &gear, lanes);
+                assert_eq!(
+                    actual,
+                    expected,
+                    "case {} with {} lanes, sizes {}/{}/{}, masks {:x}/{:x}, {} bytes",
+                    case,
+                    lanes.lanes,
+                    min_size,
+                    avg_size,
+                    max_size,
+                    mask_s,
+                    mask_l,
+                    source.len()
+                );
+            }
+
+            let actual = cut(
+                &source,
+                min_size,
+                avg_size,
+                max_size,
+                mask_s,
+                mask_l,
+                mask_s << 1,
+                mask_l << 1,
+                &gear,
+            );
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_stream_next_chunk() {
+        let mut source = vec![0; 200_000];
+        blake3::Hasher::new().finalize_xof().fill(&mut source);
+        let expected = FastCDC::new(&source, 4096, 16384, 65535).collect::<Vec<_>>();
+
+        // A buffer left over from a longer source must not leak into the next.
+        let buffer = vec![0xff; 65535];
+        let mut chunker = StreamCDC::new(source.as_slice(), 4096, 16384, 65535).with_buffer(buffer);
+        let mut index = 0;
+        while let Some(result) = chunker.next_chunk() {
+            let chunk = result.unwrap();
+            assert_eq!(chunk.hash, expected[index].hash);
+            assert_eq!(chunk.offset, expected[index].offset as u64);
+            assert_eq!(chunk.data.len(), expected[index].length);
+            assert_eq!(
+                chunk.data,
+                &source[expected[index].offset..][..expected[index].length]
+            );
+            index += 1;
+        }
+        assert_eq!(index, expected.len());
+        assert!(chunker.into_buffer().capacity() >= 65535);
+    }
 }
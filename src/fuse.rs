@@ -0,0 +1,576 @@
+//! Minimal read-only FUSE server speaking the kernel protocol directly over
+//! `/dev/fuse`. Only the requests needed to browse and read a filesystem are
+//! implemented, everything else is answered with `ENOSYS`.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+
+use log::debug;
+
+pub const ROOT_ID: u64 = 1;
+
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+const MAX_WRITE: u32 = 128 * 1024;
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+
+/// How long the kernel may cache entries and attributes. Snapshots never
+/// change, so this only bounds memory held by the kernel.
+const TTL_SECS: u64 = 60;
+
+/// Lets the kernel keep cached pages of a file across opens.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_READLINK: u32 = 5;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// Attributes of an inode as reported to the kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    /// Device number of device nodes, as from `st_rdev`.
+    pub rdev: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+}
+
+pub struct DirEntry {
+    pub ino: u64,
+    pub mode: u32,
+    pub name: Vec<u8>,
+}
+
+/// Operations of a read-only filesystem. Errors are errno values.
+pub trait Filesystem {
+    fn lookup(&mut self, parent: u64, name: &[u8]) -> Result<Attr, i32>;
+    fn getattr(&mut self, ino: u64) -> Result<Attr, i32>;
+    fn readlink(&mut self, ino: u64) -> Result<Vec<u8>, i32>;
+    fn open(&mut self, ino: u64) -> Result<u64, i32>;
+    fn read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32>;
+    fn release(&mut self, fh: u64);
+    fn readdir(&mut self, ino: u64) -> Result<Vec<DirEntry>, i32>;
+}
+
+/// Mounts a filesystem read-only and serves requests until it is unmounted.
+/// Root mounts with mount(2) directly, anyone else through the `fusermount3`
+/// or `fusermount` setuid helper. Only the mounting user may access it unless
+/// `allow_other` is set.
+pub fn mount(mountpoint: &Path, allow_other: bool, fs: &mut dyn Filesystem) {
+    let mut device = if unsafe { libc::geteuid() } == 0 {
+        mount_directly(mountpoint, allow_other)
+    } else {
+        mount_with_helper(mountpoint, allow_other)
+    };
+
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    loop {
+        let len = match device.read(&mut buffer) {
+            Ok(len) => len,
+            // The request was interrupted before it could be read.
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => continue,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            // The filesystem has been unmounted.
+            Err(err) if err.raw_os_error() == Some(libc::ENODEV) => break,
+            Err(err) => panic!("failed to read fuse request: {}", err),
+        };
+
+        if !dispatch(&mut device, fs, &buffer[..len]) {
+            break;
+        }
+    }
+}
+
+fn mount_directly(mountpoint: &Path, allow_other: bool) -> File {
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .unwrap();
+
+    let target = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+    let mut options = format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+    );
+    if allow_other {
+        options.push_str(",allow_other");
+    }
+
+    let options = CString::new(options).unwrap();
+    let result = unsafe {
+        libc::mount(
+            c"casb".as_ptr(),
+            target.as_ptr(),
+            c"fuse.casb".as_ptr(),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr().cast(),
+        )
+    };
+
+    if result != 0 {
+        panic!(
+            "failed to mount {:?}: {}",
+            mountpoint,
+            io::Error::last_os_error()
+        );
+    }
+
+    device
+}
+
+/// Has the setuid helper of libfuse open `/dev/fuse` and mount it, which
+/// hands the device back over the socket named by `_FUSE_COMMFD`.
+fn mount_with_helper(mountpoint: &Path, allow_other: bool) -> File {
+    let mut fds = [0; 2];
+    let result = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if result != 0 {
+        panic!("failed to create socket: {}", io::Error::last_os_error());
+    }
+
+    // Safety: both descriptors were just created and are owned by nobody else.
+    let (socket, helper_socket) =
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // The helper inherits its end of the socket.
+    unsafe { libc::fcntl(helper_socket.as_raw_fd(), libc::F_SETFD, 0) };
+
+    let mut options = "ro,nosuid,nodev,fsname=casb,subtype=casb,default_permissions".to_owned();
+    if allow_other {
+        options.push_str(",allow_other");
+    }
+
+    let status = ["fusermount3", "fusermount"]
+        .into_iter()
+        .find_map(|helper| {
+            match Command::new(helper)
+                .arg("-o")
+                .arg(&options)
+                .arg("--")
+                .arg(mountpoint)
+                .env("_FUSE_COMMFD", helper_socket.as_raw_fd().to_string())
+                .status()
+            {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                status => Some(status.unwrap()),
+            }
+        })
+        .expect("mounting as a user other than root needs fusermount3 or fusermount");
+
+    drop(helper_socket);
+    if !status.success() {
+        panic!("failed to mount {:?}: fusermount {}", mountpoint, status);
+    }
+
+    receive_fd(&socket).expect("fusermount did not pass back the fuse device")
+}
+
+/// Receives a file descriptor sent along with a single byte.
+fn receive_fd(socket: &OwnedFd) -> Option<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // Room for one control message with one descriptor, aligned as one.
+    let mut control = [0u64; 8];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = mem::size_of_val(&control);
+
+    loop {
+        let len =
+            unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) };
+        if len > 0 {
+            break;
+        }
+        if len < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        return None;
+    }
+
+    // Safety: the kernel filled in the control messages it received.
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if header.is_null()
+            || (*header).cmsg_level != libc::SOL_SOCKET
+            || (*header).cmsg_type != libc::SCM_RIGHTS
+        {
+            return None;
+        }
+
+        let fd = libc::CMSG_DATA(header).cast::<i32>().read_unaligned();
+        Some(File::from_raw_fd(fd))
+    }
+}
+
+/// Handles a single request, returning false once the kernel is done with the
+/// filesystem.
+fn dispatch(device: &mut dyn Write, fs: &mut dyn Filesystem, request: &[u8]) -> bool {
+    let opcode = u32_at(request, 4);
+    let unique = u64_at(request, 8);
+    let ino = u64_at(request, 16);
+    let arg = &request[IN_HEADER_SIZE..];
+
+    let result = match opcode {
+        FUSE_INIT => {
+            let major = u32_at(arg, 0);
+            if major < KERNEL_VERSION {
+                Err(libc::EPROTO)
+            } else {
+                let mut out = Vec::new();
+                put_u32(&mut out, KERNEL_VERSION);
+                put_u32(&mut out, KERNEL_MINOR_VERSION);
+                put_u32(&mut out, u32_at(arg, 8));
+                put_u32(&mut out, 0);
+                out.extend_from_slice(&[0; 4]);
+                put_u32(&mut out, MAX_WRITE);
+                put_u32(&mut out, 1);
+                out.resize(64, 0);
+                Ok(out)
+            }
+        }
+        FUSE_DESTROY => {
+            reply(device, unique, Ok(Vec::new()));
+            return false;
+        }
+        FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return true,
+        FUSE_LOOKUP => {
+            let name = arg.split(|&byte| byte == 0).next().unwrap();
+            fs.lookup(ino, name).map(entry_out)
+        }
+        FUSE_GETATTR => fs.getattr(ino).map(|attr| {
+            let mut out = Vec::new();
+            put_u64(&mut out, TTL_SECS);
+            put_u64(&mut out, 0);
+            put_attr(&mut out, &attr);
+            out
+        }),
+        FUSE_READLINK => fs.readlink(ino),
+        FUSE_OPEN => fs.open(ino).map(open_out),
+        FUSE_OPENDIR => Ok(open_out(0)),
+        FUSE_READ => {
+            let fh = u64_at(arg, 0);
+            let offset = u64_at(arg, 8);
+            let size = u32_at(arg, 16);
+            fs.read(ino, fh, offset, size)
+        }
+        FUSE_RELEASE => {
+            fs.release(u64_at(arg, 0));
+            Ok(Vec::new())
+        }
+        FUSE_RELEASEDIR => Ok(Vec::new()),
+        FUSE_READDIR => {
+            let offset = u64_at(arg, 8) as usize;
+            let size = u32_at(arg, 16) as usize;
+            fs.readdir(ino)
+                .map(|entries| dirents(&entries, offset, size))
+        }
+        FUSE_STATFS => {
+            let mut out = vec![0; 80];
+            out[40..44].copy_from_slice(&512u32.to_ne_bytes());
+            out[44..48].copy_from_slice(&255u32.to_ne_bytes());
+            Ok(out)
+        }
+        _ => {
+            debug!("unsupported fuse request {}", opcode);
+            Err(libc::ENOSYS)
+        }
+    };
+
+    reply(device, unique, result);
+    true
+}
+
+fn reply(device: &mut dyn Write, unique: u64, result: Result<Vec<u8>, i32>) {
+    let (error, data) = match result {
+        Ok(data) => (0, data),
+        Err(errno) => (-errno, Vec::new()),
+    };
+
+    let mut out = Vec::with_capacity(OUT_HEADER_SIZE + data.len());
+    put_u32(&mut out, (OUT_HEADER_SIZE + data.len()) as u32);
+    out.extend_from_slice(&error.to_ne_bytes());
+    put_u64(&mut out, unique);
+    out.extend_from_slice(&data);
+
+    // Replies to requests which were interrupted in the meantime fail with
+    // ENOENT, which is harmless.
+    if let Err(err) = device.write_all(&out) {
+        debug!("failed to write fuse reply: {}", err);
+    }
+}
+
+fn entry_out(attr: Attr) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_u64(&mut out, attr.ino);
+    put_u64(&mut out, 0);
+    put_u64(&mut out, TTL_SECS);
+    put_u64(&mut out, TTL_SECS);
+    put_u64(&mut out, 0);
+    put_attr(&mut out, &attr);
+    out
+}
+
+fn open_out(fh: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_u64(&mut out, fh);
+    put_u32(&mut out, FOPEN_KEEP_CACHE);
+    put_u32(&mut out, 0);
+    out
+}
+
+/// Encodes the directory entries after `offset` which fit in `size` bytes.
+/// The offset of each entry is its position in the listing plus one.
+fn dirents(entries: &[DirEntry], offset: usize, size: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    for (i, entry) in entries.iter().enumerate().skip(offset) {
+        let len = (24 + entry.name.len()).next_multiple_of(8);
+        if out.len() + len > size {
+            break;
+        }
+
+        put_u64(&mut out, entry.ino);
+        put_u64(&mut out, i as u64 + 1);
+        put_u32(&mut out, entry.name.len() as u32);
+        put_u32(&mut out, (entry.mode & libc::S_IFMT) >> 12);
+        out.extend_from_slice(&entry.name);
+        out.resize(out.len().next_multiple_of(8), 0);
+    }
+
+    out
+}
+
+fn put_attr(out: &mut Vec<u8>, attr: &Attr) {
+    put_u64(out, attr.ino);
+    put_u64(out, attr.size);
+    put_u64(out, attr.size.div_ceil(512));
+    put_u64(out, attr.atime.max(0) as u64);
+    put_u64(out, attr.mtime.max(0) as u64);
+    put_u64(out, attr.ctime.max(0) as u64);
+    out.extend_from_slice(&[0; 12]);
+    put_u32(out, attr.mode);
+    put_u32(out, attr.nlink);
+    put_u32(out, attr.uid);
+    put_u32(out, attr.gid);
+    put_u32(out, encode_dev(attr.rdev));
+    put_u32(out, 4096);
+    put_u32(out, 0);
+}
+
+/// Packs a device number into the 32 bits the kernel expects, as its
+/// `new_encode_dev` does.
+fn encode_dev(rdev: u64) -> u32 {
+    let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_ne_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    struct OneFile;
+
+    impl Filesystem for OneFile {
+        fn lookup(&mut self, parent: u64, name: &[u8]) -> Result<Attr, i32> {
+            match (parent, name) {
+                (ROOT_ID, b"file") => self.getattr(2),
+                _ => Err(libc::ENOENT),
+            }
+        }
+
+        fn getattr(&mut self, ino: u64) -> Result<Attr, i32> {
+            Ok(Attr {
+                ino,
+                size: 1000,
+                mode: libc::S_IFREG | 0o644,
+                nlink: 1,
+                rdev: 0,
+                uid: 1000,
+                gid: 100,
+                atime: 1,
+                mtime: 2,
+                ctime: 3,
+            })
+        }
+
+        fn readlink(&mut self, _: u64) -> Result<Vec<u8>, i32> {
+            Err(libc::EINVAL)
+        }
+
+        fn open(&mut self, _: u64) -> Result<u64, i32> {
+            Ok(7)
+        }
+
+        fn read(&mut self, _: u64, _: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32> {
+            Ok(vec![offset as u8; size as usize])
+        }
+
+        fn release(&mut self, _: u64) {}
+
+        fn readdir(&mut self, _: u64) -> Result<Vec<DirEntry>, i32> {
+            Ok(vec![DirEntry {
+                ino: 2,
+                mode: libc::S_IFREG,
+                name: b"file".to_vec(),
+            }])
+        }
+    }
+
+    /// Sends a request as the kernel would and returns the error and data of
+    /// the reply, checking its header.
+    fn request(opcode: u32, ino: u64, arg: &[u8]) -> (i32, Vec<u8>) {
+        let mut request = Vec::new();
+        put_u32(&mut request, (IN_HEADER_SIZE + arg.len()) as u32);
+        put_u32(&mut request, opcode);
+        put_u64(&mut request, 42);
+        put_u64(&mut request, ino);
+        request.resize(IN_HEADER_SIZE, 0);
+        request.extend_from_slice(arg);
+
+        let mut reply = Vec::new();
+        assert!(dispatch(&mut reply, &mut OneFile, &request));
+        assert_eq!(u32_at(&reply, 0) as usize, reply.len());
+        assert_eq!(u64_at(&reply, 8), 42);
+        let error = i32::from_ne_bytes(reply[4..8].try_into().unwrap());
+        (error, reply[OUT_HEADER_SIZE..].to_vec())
+    }
+
+    #[test]
+    fn test_reply_layouts() {
+        // fuse_init_out as of protocol 7.31.
+        let mut init = Vec::new();
+        put_u32(&mut init, 7);
+        put_u32(&mut init, 31);
+        put_u32(&mut init, 4096);
+        put_u32(&mut init, 0);
+        let (error, out) = request(FUSE_INIT, 0, &init);
+        assert_eq!((error, out.len()), (0, 64));
+        assert_eq!((u32_at(&out, 0), u32_at(&out, 8)), (7, 4096));
+        assert_eq!(u32_at(&out, 20), MAX_WRITE);
+
+        // fuse_entry_out, then the 88 bytes of fuse_attr.
+        let (error, out) = request(FUSE_LOOKUP, ROOT_ID, b"file\0");
+        assert_eq!((error, out.len()), (0, 40 + 88));
+        assert_eq!(u64_at(&out, 0), 2);
+        let attr = &out[40..];
+        assert_eq!((u64_at(attr, 0), u64_at(attr, 8)), (2, 1000));
+        assert_eq!(u64_at(attr, 40), 3);
+        assert_eq!(u32_at(attr, 60), libc::S_IFREG | 0o644);
+        assert_eq!((u32_at(attr, 68), u32_at(attr, 72)), (1000, 100));
+        assert_eq!(request(FUSE_LOOKUP, ROOT_ID, b"missing\0").0, -libc::ENOENT);
+
+        // fuse_attr_out.
+        let (error, out) = request(FUSE_GETATTR, 2, &[0; 16]);
+        assert_eq!((error, out.len()), (0, 16 + 88));
+
+        // fuse_open_out.
+        let (error, out) = request(FUSE_OPEN, 2, &[0; 8]);
+        assert_eq!((error, out.len(), u64_at(&out, 0)), (0, 16, 7));
+
+        // fuse_dirent, padded to eight bytes.
+        let mut read = Vec::new();
+        put_u64(&mut read, 0);
+        put_u64(&mut read, 0);
+        put_u32(&mut read, 4096);
+        read.resize(40, 0);
+        let (error, out) = request(FUSE_READDIR, ROOT_ID, &read);
+        assert_eq!((error, out.len()), (0, 32));
+        assert_eq!((u64_at(&out, 0), u64_at(&out, 8)), (2, 1));
+        assert_eq!(&out[24..28], b"file");
+
+        assert_eq!(request(1000, ROOT_ID, &[]).0, -libc::ENOSYS);
+    }
+
+    #[test]
+    fn test_receive_fd() {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let file = tempfile::tempfile().unwrap();
+
+        let mut control = [0u64; 8];
+        let mut byte = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr().cast(),
+            iov_len: 1,
+        };
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = unsafe { libc::CMSG_SPACE(4) } as usize;
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(4) as usize;
+            libc::CMSG_DATA(header)
+                .cast::<i32>()
+                .write_unaligned(file.as_raw_fd());
+            assert_eq!(libc::sendmsg(theirs.as_raw_fd(), &message, 0), 1);
+        }
+
+        let received = receive_fd(&OwnedFd::from(ours)).unwrap();
+        assert_ne!(received.as_raw_fd(), file.as_raw_fd());
+        assert_eq!(
+            received.metadata().unwrap().ino(),
+            file.metadata().unwrap().ino()
+        );
+    }
+}
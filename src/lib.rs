@@ -0,0 +1,16 @@
+//! Internals of the `casb` binary, exposed for its benchmarks only. None of
+//! this is a stable interface.
+
+mod cache;
+#[doc(hidden)]
+pub mod chunker;
+#[doc(hidden)]
+pub mod cmd;
+#[doc(hidden)]
+pub mod fastcdc;
+mod fuse;
+#[doc(hidden)]
+pub mod pack;
+#[doc(hidden)]
+pub mod repo;
+mod useg;
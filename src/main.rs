@@ -1,19 +1,11 @@
-mod cache;
-mod fastcdc;
-mod pack;
-mod repo;
-mod useg;
-
-use std::collections::BTreeSet;
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::path::PathBuf;
 
+use casb::cmd;
+use casb::pack::Compression;
+use casb::repo::{ChunkerAlgorithm, ChunkerParams, Cipher, Key, PackSize, Repository};
 use clap::{Parser, Subcommand};
 use log::{Level, debug};
-use pack::Packer;
-use repo::{BlobKind, Index, Key, Node, NodeKind, PackInfoEntry, Tree};
-use useg::{UPath, USeg};
-use walkdir::WalkDir;
 
 /// Properly designed backup based on content addressable storage.
 #[derive(Parser, Debug)]
@@ -22,159 +14,406 @@ struct Args {
     #[command(flatten)]
     verbose: clap_verbosity_flag::Verbosity,
 
+    #[command(flatten)]
+    credentials: cmd::Credentials,
+
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
+    /// create a new repository
+    Init {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
+
+        /// cipher to seal objects with, chacha12-blake3 or xchacha20-poly1305
+        #[arg(long, default_value = "chacha12-blake3")]
+        cipher: Cipher,
+
+        /// algorithm files are split into chunks with: fastcdc-2020,
+        /// fastcdc-2016, buzhash, or fixed for block device images, which cuts
+        /// every average chunk size bytes
+        #[arg(long, default_value = "fastcdc-2020")]
+        chunker: ChunkerAlgorithm,
+
+        /// smallest chunk files are split into, like 512K
+        #[arg(long)]
+        chunk_min_size: Option<String>,
+
+        /// average chunk size to aim for, like 1M
+        #[arg(long)]
+        chunk_avg_size: Option<String>,
+
+        /// largest chunk files are split into, like 2M
+        #[arg(long)]
+        chunk_max_size: Option<String>,
+
+        /// chunk size normalization level, from 0 to 3, higher keeps chunks
+        /// closer to the average
+        #[arg(long)]
+        normalization: Option<u32>,
+
+        /// size at which packs are finished, like 64M for object storage
+        #[arg(long)]
+        pack_size: Option<String>,
+
+        /// size no pack grows beyond unless it holds a single larger blob,
+        /// twice the pack size by default
+        #[arg(long)]
+        pack_size_max: Option<String>,
+
+        /// directory to keep data packs in, relative to the repo, for storage
+        /// which is slow or costly to read from; listing snapshots never
+        /// touches it
+        #[arg(long)]
+        cold_storage: Option<PathBuf>,
+    },
     /// does testing things
     Backup {
         /// path to backup
-        #[arg(short, long)]
-        path: PathBuf,
+        #[arg(short, long, required_unless_present_any = ["stdin", "stdin_from_command", "tar"])]
+        path: Option<PathBuf>,
 
         /// path to repo
         #[arg(short, long)]
         repo: PathBuf,
+
+        /// import a tar archive, optionally gzip or zstd compressed
+        #[arg(long, conflicts_with = "path")]
+        tar: Option<PathBuf>,
+
+        /// back up a single file read from stdin
+        #[arg(long, conflicts_with_all = ["path", "tar"])]
+        stdin: bool,
+
+        /// back up the output of the command given after `--`
+        #[arg(long, conflicts_with_all = ["path", "tar", "stdin"], requires = "command")]
+        stdin_from_command: bool,
+
+        /// name of the file read from stdin or a command
+        #[arg(long, default_value = "stdin")]
+        stdin_filename: String,
+
+        /// compression of blobs: off, auto, max or a zstd level from 1 to 22
+        #[arg(long, default_value = "auto")]
+        compression: Compression,
+
+        /// keep files up to this size inline in their directory's tree, 0 to
+        /// store every file in blobs
+        #[arg(long, default_value = "128")]
+        inline_size: String,
+
+        /// leave out atimes, ctimes, inodes, link counts and the time of files
+        /// read from stdin or a command, so backing up unchanged files or an
+        /// identical copy of them gives the same root tree
+        #[arg(long)]
+        deterministic: bool,
+
+        /// command and arguments for --stdin-from-command
+        #[arg(last = true)]
+        command: Vec<String>,
     },
-}
+    /// list the contents of a snapshot
+    Ls {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
 
-fn main() {
-    let args = Args::parse();
-    env_logger::builder()
-        .filter(None, Level::Debug.to_level_filter())
-        .format_timestamp_millis()
-        .init();
+        /// snapshot id prefix or `latest`
+        snapshot: String,
 
-    debug!("and we're alive!");
+        /// directory or file within the snapshot
+        path: Option<String>,
 
-    match args.command {
-        Command::Backup { path, repo } => {
-            run_backup(&path, &repo);
-        }
-    }
-}
+        /// list subdirectories recursively
+        #[arg(long)]
+        recursive: bool,
 
-fn run_backup(path: &Path, repo: &Path) {
-    let mut trees = Vec::new();
-    let mut file_packer = Packer::new();
-    let mut tree_packer = Packer::new();
+        /// show mode, owner, size and modification time
+        #[arg(short, long)]
+        long: bool,
 
-    let data_path = repo.join("data");
-    let tree_path = repo.join("tree");
-    let index_path = repo.join("index");
+        /// print one json object per entry
+        #[arg(long, conflicts_with = "long")]
+        json: bool,
+    },
+    /// search for entries across snapshots
+    Find {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
 
-    fs::create_dir_all(repo).unwrap();
-    fs::create_dir_all(&data_path).unwrap();
-    fs::create_dir_all(&tree_path).unwrap();
-    fs::create_dir_all(&index_path).unwrap();
+        /// glob matched against entry names
+        pattern: Option<String>,
 
-    let key = Key { bytes: [0; 32] };
+        /// only search these snapshots, defaults to all
+        #[arg(short, long)]
+        snapshot: Vec<String>,
 
-    let mut index = Index {
-        supersedes: Vec::new(),
-        packs: Vec::new(),
-    };
+        /// only entries modified at or after this time
+        #[arg(long)]
+        newer: Option<String>,
 
-    for entry in WalkDir::new(path).sort_by_file_name() {
-        let entry = entry.unwrap();
-        let upath = UPath::from_path(entry.path());
+        /// only entries modified before this time
+        #[arg(long)]
+        older: Option<String>,
 
-        debug!("entry path: {:?}", entry.path());
+        /// only files of at least this size
+        #[arg(long)]
+        min_size: Option<String>,
 
-        if entry.file_type().is_dir() {
-            //let subtree = USeg::from_segment_bytes(upath.last_segment());
-            //let kind = NodeKind::Dir { subtree };
-            //add_node(&mut trees, kind, &upath);
+        /// only files of at most this size
+        #[arg(long)]
+        max_size: Option<String>,
 
-            let tree = Tree {
-                nodes: BTreeSet::new(),
-            };
+        /// only files containing a blob with this id prefix
+        #[arg(long)]
+        blob: Vec<String>,
 
-            trees.push((upath.clone(), tree));
-        }
+        /// only directories with this tree id prefix
+        #[arg(long)]
+        tree: Vec<String>,
+    },
+    /// show the differences between two snapshots
+    Diff {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
 
-        if entry.file_type().is_file() {
-            let mut file = fs::OpenOptions::new()
-                .read(true)
-                .open(entry.path())
-                .unwrap();
+        /// snapshot to compare from
+        a: String,
 
-            let mut content = Vec::new();
+        /// snapshot to compare to
+        b: String,
 
-            for (entry, chunk) in pack::split_to_data_blobs(&mut file) {
-                content.push(entry.id);
-                file_packer.add_blob(entry, &chunk);
+        /// print the changes and summary as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// write a file or directory from a snapshot to stdout
+    Dump {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
 
-                if file_packer.should_pack() {
-                    finish_pack(&mut file_packer, &mut index, &key, &data_path);
-                }
-            }
+        /// snapshot id prefix or `latest`
+        snapshot: String,
 
-            let kind = NodeKind::File { content };
+        /// file or directory within the snapshot
+        path: String,
 
-            add_node(&mut trees, kind, &upath);
-        }
-    }
+        /// archive format used for directories
+        #[arg(long, value_enum, default_value_t = cmd::dump::Archive::Tar)]
+        archive: cmd::dump::Archive,
+    },
+    /// print a decrypted repository object
+    Cat {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
 
-    for (_path, tree) in trees.into_iter().rev() {
-        let data = rmp_serde::to_vec(&tree).unwrap();
-        let id = blake3::hash(&data).into();
-        let entry = PackInfoEntry {
-            id,
-            kind: BlobKind::Tree,
-            size_uncompressed: data.len(),
-            size_compressed: None,
-        };
-
-        tree_packer.add_blob(entry, &data);
-        if tree_packer.should_pack() {
-            finish_pack(&mut tree_packer, &mut index, &key, &tree_path);
-        }
-    }
+        #[command(subcommand)]
+        object: cmd::cat::Object,
+    },
+    /// manage the passwords which unlock a repository
+    Key {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
 
-    finish_pack(&mut file_packer, &mut index, &key, &data_path);
-    finish_pack(&mut tree_packer, &mut index, &key, &tree_path);
+        #[command(subcommand)]
+        action: cmd::key::Action,
+    },
+    /// manage the zstd dictionaries small blobs are compressed with
+    Dict {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
 
-    let index_data = rmp_serde::to_vec(&index).unwrap();
-    let index_id = blake3::hash(&index_data);
-    let index_path = index_path.join(format!("{}.index", index_id));
-    fs::write(index_path, index_data).unwrap();
-}
+        #[command(subcommand)]
+        action: cmd::dict::Action,
+    },
+    /// browse snapshots through a read-only fuse mount
+    Mount {
+        /// path to repo
+        #[arg(short, long)]
+        repo: PathBuf,
 
-fn add_node(trees: &mut [(UPath, Tree)], kind: NodeKind, upath: &UPath) {
-    let node = Node {
-        name: USeg::from_segment_bytes(upath.last_segment()),
-        mode: 0,
-        mtime: 0,
-        atime: 0,
-        ctime: 0,
-        uid: 0,
-        gid: 0,
-        user: String::new(),
-        inode: 0,
-        kind,
-    };
-
-    debug!("node: {:?}", node);
-
-    let parent = upath.parent();
-    let tree_idx = trees.iter().position(|(path, _)| path == &parent).unwrap();
-    trees[tree_idx].1.nodes.insert(node);
+        /// empty directory to mount on
+        mountpoint: PathBuf,
+
+        /// let users other than the one mounting browse the snapshots
+        #[arg(long)]
+        allow_other: bool,
+    },
 }
 
-fn finish_pack(packer: &mut Packer, index: &mut Index, key: &Key, to: &Path) {
-    let (pack, data) = packer.finish(key);
-    let pack_path = to.join(format!("{}.pack", pack.id.to_hex()));
+fn main() {
+    let args = Args::parse();
+    env_logger::builder()
+        .filter(None, Level::Debug.to_level_filter())
+        .format_timestamp_millis()
+        .init();
+
+    debug!("and we're alive!");
 
-    debug!("writing pack {:?}", index);
+    match args.command {
+        Command::Init {
+            repo,
+            cipher,
+            chunker,
+            chunk_min_size,
+            chunk_avg_size,
+            chunk_max_size,
+            normalization,
+            pack_size,
+            pack_size_max,
+            cold_storage,
+        } => {
+            let defaults = ChunkerParams::default();
+            let size = |size: Option<String>, default| {
+                size.map_or(default, |size| {
+                    u32::try_from(cmd::parse_size(&size))
+                        .unwrap_or_else(|_| panic!("chunk size {:?} is too large", size))
+                })
+            };
 
-    index.packs.push(pack);
+            let chunker = ChunkerParams {
+                min_size: size(chunk_min_size, defaults.min_size),
+                avg_size: size(chunk_avg_size, defaults.avg_size),
+                max_size: size(chunk_max_size, defaults.max_size),
+                level: normalization.unwrap_or(defaults.level),
+                algorithm: chunker,
+            };
+            chunker.validate();
+
+            let target = pack_size.map_or(PackSize::default().target, |size| {
+                cmd::parse_size(&size) as usize
+            });
+            let pack_size = PackSize {
+                target,
+                max: pack_size_max.map_or(2 * target, |size| cmd::parse_size(&size) as usize),
+            };
+            pack_size.validate();
+
+            let password = args.credentials.new_password();
+            let repository = Repository::init(
+                &repo,
+                Key::generate(),
+                cipher,
+                chunker,
+                pack_size,
+                cold_storage,
+            );
+            repository.write_key(&cmd::key::new_recipe(&repository, &password));
+            println!("created repository {}", repository.config().id);
+        }
+        Command::Backup {
+            path,
+            repo,
+            tar,
+            stdin,
+            stdin_from_command,
+            stdin_filename,
+            compression,
+            inline_size,
+            deterministic,
+            command,
+        } => {
+            let repository = args.credentials.unlock(&repo);
+            let options = cmd::backup::Options {
+                compression,
+                inline_size: cmd::parse_size(&inline_size),
+                deterministic,
+            };
 
-    if fs::exists(&pack_path).unwrap() {
-        panic!();
-    }
+            if let Some(archive) = tar {
+                cmd::backup::run_tar(&repository, &archive, options);
+            } else if stdin {
+                let mut reader = io::stdin().lock();
+                cmd::backup::run_stdin(&repository, &stdin_filename, &mut reader, options);
+            } else if stdin_from_command {
+                cmd::backup::run_command(&repository, &stdin_filename, &command, options);
+            } else {
+                cmd::backup::run(&repository, &path.unwrap(), options);
+            }
+        }
+        Command::Ls {
+            repo,
+            snapshot,
+            path,
+            recursive,
+            long,
+            json,
+        } => {
+            let repository = args.credentials.unlock(&repo);
+            let format = match (long, json) {
+                (_, true) => cmd::ls::Format::Json,
+                (true, false) => cmd::ls::Format::Long,
+                (false, false) => cmd::ls::Format::Short,
+            };
+
+            cmd::ls::run(&repository, &snapshot, path.as_deref(), recursive, format);
+        }
+        Command::Find {
+            repo,
+            pattern,
+            snapshot,
+            newer,
+            older,
+            min_size,
+            max_size,
+            blob,
+            tree,
+        } => {
+            let repository = args.credentials.unlock(&repo);
+            let filter = cmd::find::Filter {
+                pattern,
+                newer: newer.as_deref().map(cmd::parse_time),
+                older: older.as_deref().map(cmd::parse_time),
+                min_size: min_size.as_deref().map(cmd::parse_size),
+                max_size: max_size.as_deref().map(cmd::parse_size),
+                blobs: blob,
+                trees: tree,
+            };
 
-    fs::write(pack_path, data).unwrap();
+            cmd::find::run(&repository, &snapshot, &filter);
+        }
+        Command::Diff { repo, a, b, json } => {
+            let repository = args.credentials.unlock(&repo);
+            cmd::diff::run(&repository, &a, &b, json);
+        }
+        Command::Dump {
+            repo,
+            snapshot,
+            path,
+            archive,
+        } => {
+            let repository = args.credentials.unlock(&repo);
+            cmd::dump::run(&repository, &snapshot, &path, archive);
+        }
+        Command::Cat { repo, object } => {
+            let repository = args.credentials.unlock(&repo);
+            cmd::cat::run(&repository, object);
+        }
+        Command::Key { repo, action } => {
+            let (repository, password) = args.credentials.unlock_with_password(&repo);
+            cmd::key::run(&repository, password.as_deref(), action);
+        }
+        Command::Dict { repo, action } => {
+            let repository = args.credentials.unlock(&repo);
+            cmd::dict::run(&repository, action);
+        }
+        Command::Mount {
+            repo,
+            mountpoint,
+            allow_other,
+        } => {
+            let repository = args.credentials.unlock(&repo);
+            cmd::mount::run(&repository, &mountpoint, allow_other);
+        }
+    }
 }
@@ -1,89 +1,492 @@
 #![allow(dead_code)]
 
 use std::io::Read;
-use std::mem;
 use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::{fmt, mem};
 
-use crate::fastcdc;
-use crate::repo::{BlobKind, IndexBlobInfo, IndexPackInfo, Key, PackInfo, PackInfoEntry};
+use zstd::bulk::Compressor;
+use zstd::zstd_safe::{self, CParameter};
+
+use crate::chunker::{Buzhash, Chunker, FastCdc2016, FastCdc2020, Fixed};
+use crate::fastcdc::{self, Gear, Normalization, StreamCDC};
+use crate::repo::{
+    BlobClass, BlobKind, ChunkerAlgorithm, ChunkerParams, Cipher, Hash, IndexBlobInfo,
+    IndexPackInfo, Key, PackInfo, PackInfoEntry, PackSize, Subkeys, begin_seal, finish_seal,
+    seal_blob,
+};
 
 const CHUNK_MIN_SIZE: u32 = 512 * 1024;
 const CHUNK_AVG_SIZE: u32 = 1024 * 1024;
 const CHUNK_MAX_SIZE: u32 = 2 * 1024 * 1024;
+const CHUNK_LEVEL: u32 = 1;
 
 const BLOB_COMPRESSION_THRESHOLD: usize = 100;
 
+/// Bytes at the start of a blob whose entropy decides whether compressing it
+/// is worth trying.
+const ENTROPY_SAMPLE_SIZE: usize = 64 * 1024;
+/// Entropy, in bits per byte, above which a blob is taken to be compressed
+/// already, like jpg, zip or mp4 files.
+const ENTROPY_THRESHOLD: f64 = 7.5;
+
+const COMPRESSION_LEVEL_AUTO: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+const COMPRESSION_LEVEL_MAX: i32 = 22;
+
+/// Largest blob compressed with a dictionary. Bigger ones have enough data of
+/// their own to compress well.
+pub const DICTIONARY_BLOB_MAX: usize = 64 * 1024;
+/// Size of trained dictionaries, the default of the zstd cli.
+const DICTIONARY_SIZE: usize = 110 * 1024;
+
+/// How blobs are compressed before they are sealed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Off,
+    /// The default level, skipping blobs which look incompressible.
+    Auto,
+    /// The highest level with long distance matching, trying every blob.
+    Max,
+    /// A given level, skipping blobs which look incompressible.
+    Level(i32),
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Compression::Off),
+            "auto" => Ok(Compression::Auto),
+            "max" => Ok(Compression::Max),
+            _ => match value.parse() {
+                Ok(level) if (1..=COMPRESSION_LEVEL_MAX).contains(&level) => {
+                    Ok(Compression::Level(level))
+                }
+                _ => Err(format!(
+                    "invalid compression {:?}, expected off, auto, max or a level from 1 to {}",
+                    value, COMPRESSION_LEVEL_MAX
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Off => f.write_str("off"),
+            Compression::Auto => f.write_str("auto"),
+            Compression::Max => f.write_str("max"),
+            Compression::Level(level) => level.fmt(f),
+        }
+    }
+}
+
+impl Compression {
+    /// The level to compress a blob at, or `None` if it isn't worth trying.
+    fn level(self, data: &[u8]) -> Option<i32> {
+        let level = match self {
+            Compression::Off => return None,
+            Compression::Auto => COMPRESSION_LEVEL_AUTO,
+            Compression::Max => COMPRESSION_LEVEL_MAX,
+            Compression::Level(level) => level,
+        };
+
+        if data.len() < BLOB_COMPRESSION_THRESHOLD {
+            return None;
+        }
+
+        if self != Compression::Max
+            && entropy(&data[..data.len().min(ENTROPY_SAMPLE_SIZE)]) > ENTROPY_THRESHOLD
+        {
+            return None;
+        }
+
+        Some(level)
+    }
+
+    fn compressor(self, level: i32, dictionary: Option<&[u8]>) -> Compressor<'static> {
+        let mut compressor = match dictionary {
+            Some(dictionary) => Compressor::with_dictionary(level, dictionary),
+            None => Compressor::new(level),
+        }
+        .unwrap();
+        if self == Compression::Max {
+            compressor
+                .set_parameter(CParameter::EnableLongDistanceMatching(true))
+                .unwrap();
+        }
+
+        compressor
+    }
+}
+
+/// Appends the compressed data to `out` and returns true, unless compressing
+/// doesn't make it any smaller, in which case `out` is left as it was.
+fn compress_into(compressor: &mut Compressor, data: &[u8], out: &mut Vec<u8>) -> bool {
+    let start = out.len();
+    out.resize(start + zstd_safe::compress_bound(data.len()), 0);
+
+    let written = compressor
+        .compress_to_buffer(data, &mut out[start..])
+        .unwrap();
+    if written < data.len() {
+        out.truncate(start + written);
+        true
+    } else {
+        out.truncate(start);
+        false
+    }
+}
+
+/// Shannon entropy of the bytes, in bits per byte.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+
+    let total = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Trains a dictionary from sample blobs, or returns `None` if there are too
+/// few of them to train one.
+pub fn train_dictionary(samples: &[Vec<u8>]) -> Option<Vec<u8>> {
+    zstd::dict::from_samples(samples, DICTIONARY_SIZE).ok()
+}
+
+/// Compresses a tree or data blob according to the policy, picking its kind
+/// and the entry describing it in a pack. Small blobs are compressed with the
+/// dictionary, if one is given.
+pub fn encode_blob(
+    id: Hash,
+    class: BlobClass,
+    data: Vec<u8>,
+    compression: Compression,
+    dictionary: Option<(Hash, &[u8])>,
+) -> (PackInfoEntry, Box<[u8]>) {
+    let mut encoded = Vec::new();
+    let entry = encode_into(
+        id,
+        class,
+        &data,
+        compression,
+        dictionary,
+        &mut Vec::new(),
+        &mut encoded,
+    );
+
+    (entry, encoded.into_boxed_slice())
+}
+
+/// Appends a blob to `out` the way [`encode_blob`] encodes it, compressing
+/// with the cached compressors.
+fn encode_into(
+    id: Hash,
+    class: BlobClass,
+    data: &[u8],
+    compression: Compression,
+    dictionary: Option<(Hash, &[u8])>,
+    compressors: &mut Vec<(Compression, Option<Hash>, Compressor<'static>)>,
+    out: &mut Vec<u8>,
+) -> PackInfoEntry {
+    let dictionary = dictionary.filter(|_| data.len() <= DICTIONARY_BLOB_MAX);
+    let start = out.len();
+
+    let mut level = compression.level(data);
+    if let Some(compression_level) = level {
+        let compressor = cached_compressor(compressors, compression, compression_level, dictionary);
+        if !compress_into(compressor, data, out) {
+            level = None;
+        }
+    }
+
+    if level.is_none() {
+        out.extend_from_slice(data);
+    }
+
+    PackInfoEntry {
+        id,
+        kind: blob_kind(class, level, dictionary.map(|(id, _)| id)),
+        size_uncompressed: data.len(),
+        size_compressed: level.and_then(|_| NonZeroUsize::new(out.len() - start)),
+    }
+}
+
+/// The kind of a blob of a class, compressed at `level` if it is compressed.
+fn blob_kind(class: BlobClass, level: Option<i32>, dictionary: Option<Hash>) -> BlobKind {
+    match (class, level, dictionary) {
+        (BlobClass::Tree, Some(level), Some(dictionary)) => {
+            BlobKind::TreeZstdDictionary(level, dictionary)
+        }
+        (BlobClass::Data, Some(level), Some(dictionary)) => {
+            BlobKind::DataZstdDictionary(level, dictionary)
+        }
+        (BlobClass::Tree, Some(level), None) => BlobKind::TreeZstd(level),
+        (BlobClass::Data, Some(level), None) => BlobKind::DataZstd(level),
+        (BlobClass::Tree, None, _) => BlobKind::Tree,
+        (BlobClass::Data, None, _) => BlobKind::Data,
+    }
+}
+
 const PACK_SIZE_TARGET: usize = 8 * 1024 * 1024;
 const PACK_SIZE_MAX: usize = 16 * 1024 * 1024;
+/// Largest pack size accepted. Packers build a whole pack in memory, one per
+/// blob class, and backends store it in a single write, so this bounds both.
+const PACK_SIZE_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// Repositories from before the chunker was configurable were chunked with
+/// these.
+impl Default for ChunkerParams {
+    fn default() -> Self {
+        ChunkerParams {
+            min_size: CHUNK_MIN_SIZE,
+            avg_size: CHUNK_AVG_SIZE,
+            max_size: CHUNK_MAX_SIZE,
+            level: CHUNK_LEVEL,
+            algorithm: ChunkerAlgorithm::FastCdc2020,
+        }
+    }
+}
+
+impl ChunkerParams {
+    /// Panics unless the sizes are within the bounds FastCDC supports and in
+    /// order, and the level is a known normalization level.
+    pub fn validate(&self) {
+        let check = |name, value, min, max| {
+            if !(min..=max).contains(&value) {
+                panic!(
+                    "chunk {} size {} is outside of {}..={}",
+                    name, value, min, max
+                );
+            }
+        };
+
+        check(
+            "minimum",
+            self.min_size,
+            fastcdc::MINIMUM_MIN,
+            fastcdc::MINIMUM_MAX,
+        );
+        check(
+            "average",
+            self.avg_size,
+            fastcdc::AVERAGE_MIN,
+            fastcdc::AVERAGE_MAX,
+        );
+        check(
+            "maximum",
+            self.max_size,
+            fastcdc::MAXIMUM_MIN,
+            fastcdc::MAXIMUM_MAX,
+        );
+
+        if self.min_size > self.avg_size || self.avg_size > self.max_size {
+            panic!(
+                "chunk sizes {}/{}/{} are not in increasing order",
+                self.min_size, self.avg_size, self.max_size
+            );
+        }
+
+        self.normalization();
+    }
+
+    fn normalization(&self) -> Normalization {
+        match self.level {
+            0 => Normalization::Level0,
+            1 => Normalization::Level1,
+            2 => Normalization::Level2,
+            3 => Normalization::Level3,
+            _ => panic!("normalization level {} is not 0 to 3", self.level),
+        }
+    }
+}
+
+/// Repositories from before pack sizes were configurable were written with
+/// these, which suit local disks.
+impl Default for PackSize {
+    fn default() -> Self {
+        PackSize {
+            target: PACK_SIZE_TARGET,
+            max: PACK_SIZE_MAX,
+        }
+    }
+}
+
+impl PackSize {
+    /// Panics unless the target is positive and no larger than the maximum,
+    /// and the maximum is within what the pack format can address.
+    pub fn validate(&self) {
+        if self.target == 0 || self.target > self.max {
+            panic!(
+                "pack size target {} must be positive and at most the maximum {}",
+                self.target, self.max
+            );
+        }
+
+        if self.max > PACK_SIZE_LIMIT {
+            panic!(
+                "pack size maximum {} is larger than {}",
+                self.max, PACK_SIZE_LIMIT
+            );
+        }
+    }
+}
+
+/// Returned by [`Packer::add_blob`] when a blob doesn't fit, so the caller can
+/// finish the pack and add the blob to the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackFull;
 
 pub struct Packer {
+    key: Key,
+    cipher: Cipher,
+    pack_size: PackSize,
     entries: Vec<PackInfoEntry>,
     buffer: Vec<u8>,
+    /// Compressors kept for reuse by [`Packer::pack_blob`], by the compression
+    /// and dictionary they were set up with, since setting one up costs more
+    /// than compressing a small blob.
+    compressors: Vec<(Compression, Option<Hash>, Compressor<'static>)>,
+    /// Size of the pack so far, counting the header entry of every blob.
     size: usize,
+    /// Part of `size` which isn't blob data: the envelope every blob is sealed
+    /// in and its entry in the header.
+    overhead: usize,
 }
 
 impl Packer {
-    pub fn new() -> Self {
+    pub fn new(key: Key, cipher: Cipher, pack_size: PackSize) -> Self {
         Self {
+            key,
+            cipher,
+            pack_size,
             entries: Vec::new(),
             buffer: Vec::new(),
+            compressors: Vec::new(),
             size: 0,
+            overhead: 0,
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn overhead(&self) -> usize {
+        self.overhead
+    }
+
     pub fn should_pack(&self) -> bool {
-        self.size >= PACK_SIZE_TARGET
+        self.size >= self.pack_size.target
     }
 
     pub fn free_space(&self) -> usize {
-        PACK_SIZE_MAX - self.size
+        self.pack_size.max.saturating_sub(self.size)
+    }
+
+    /// Space a blob takes besides its data: the envelope it is sealed in and
+    /// its entry in the header.
+    fn blob_overhead(&self, entry: &PackInfoEntry) -> usize {
+        self.cipher.overhead() + rmp_serde::to_vec(entry).unwrap().len()
     }
 
-    pub fn add_blob(&mut self, entry: PackInfoEntry, data: &[u8]) {
-        if self.size + data.len() > PACK_SIZE_MAX {
-            panic!();
+    /// Adds a blob, unless it would grow the pack beyond its maximum size. An
+    /// empty packer takes any blob, so one larger than the maximum gets a pack
+    /// of its own.
+    pub fn add_blob(&mut self, entry: PackInfoEntry, data: &[u8]) -> Result<(), PackFull> {
+        let overhead = self.blob_overhead(&entry);
+
+        if !self.is_empty() && self.size + data.len() + overhead > self.pack_size.max {
+            return Err(PackFull);
         }
 
+        let sealed = seal_blob(data, &self.key, self.cipher);
         self.entries.push(entry);
-        self.size += data.len();
-        self.buffer.extend_from_slice(data);
+        self.size += data.len() + overhead;
+        self.overhead += overhead;
+        self.buffer.extend_from_slice(&sealed);
+        Ok(())
     }
 
-    #[allow(unused_variables)]
-    pub fn finish(&mut self, key: &Key) -> (IndexPackInfo, Box<[u8]>) {
-        let mut cursor = 0;
-        let mut ies = Vec::new();
+    /// Compresses and seals a blob straight into the pack, like
+    /// [`encode_blob`] followed by [`Packer::add_blob`] but without copying
+    /// the blob in between. Returns the entry describing it in the pack, or
+    /// [`PackFull`] before compressing anything if the blob might not fit.
+    /// Compressed blobs are only kept when smaller, so that is decided from
+    /// the size the blob would take uncompressed.
+    pub fn pack_blob(
+        &mut self,
+        id: Hash,
+        class: BlobClass,
+        data: &[u8],
+        compression: Compression,
+        dictionary: Option<(Hash, &[u8])>,
+    ) -> Result<PackInfoEntry, PackFull> {
+        let level = compression.level(data);
+        let bound = PackInfoEntry {
+            id,
+            kind: blob_kind(
+                class,
+                level,
+                dictionary
+                    .filter(|_| data.len() <= DICTIONARY_BLOB_MAX)
+                    .map(|(id, _)| id),
+            ),
+            size_uncompressed: data.len(),
+            size_compressed: level.and_then(|_| NonZeroUsize::new(data.len())),
+        };
 
-        for blob in &self.entries {
-            let length = match blob.size_compressed {
-                Some(size) => size.get(),
-                None => blob.size_uncompressed,
-            };
+        if !self.is_empty()
+            && self.size + data.len() + self.blob_overhead(&bound) > self.pack_size.max
+        {
+            return Err(PackFull);
+        }
 
-            let length_uncompressed = if blob.size_compressed.is_some() {
-                let size_uncompressed = NonZeroUsize::new(blob.size_uncompressed).unwrap();
-                Some(size_uncompressed)
-            } else {
-                None
-            };
+        if self.is_empty() {
+            self.buffer.reserve(self.pack_size.target);
+        }
 
-            let ib = IndexBlobInfo {
-                id: blob.id,
-                kind: blob.kind,
-                offset: cursor,
-                length,
-                length_uncompressed,
-            };
+        let start = begin_seal(&mut self.buffer, self.cipher);
+        let body = self.buffer.len();
+        let entry = encode_into(
+            id,
+            class,
+            data,
+            compression,
+            dictionary,
+            &mut self.compressors,
+            &mut self.buffer,
+        );
+        let length = self.buffer.len() - body;
+        let overhead = self.blob_overhead(&entry);
 
-            cursor += length;
-            ies.push(ib)
-        }
+        finish_seal(&mut self.buffer, start, &self.key, self.cipher);
+        self.entries.push(entry);
+        self.size += length + overhead;
+        self.overhead += overhead;
+        Ok(entry)
+    }
 
+    pub fn finish(&mut self) -> (IndexPackInfo, Box<[u8]>) {
         let info = PackInfo {
             blobs: mem::take(&mut self.entries),
         };
 
-        let header = rmp_serde::to_vec(&info).unwrap();
+        let header = seal_blob(&rmp_serde::to_vec(&info).unwrap(), &self.key, self.cipher);
         let header_len = (header.len() as u32).to_le_bytes();
 
         self.buffer.extend_from_slice(&header);
@@ -92,44 +495,348 @@ impl Packer {
         let data = mem::take(&mut self.buffer).into_boxed_slice();
         let id = blake3::hash(&data).into();
 
-        let index = IndexPackInfo { id, blobs: ies };
+        let index = index_pack_info(id, &info.blobs, self.cipher);
 
         self.size = 0;
+        self.overhead = 0;
         (index, data)
     }
 }
 
-pub fn split_to_data_blobs(
-    data: &mut dyn Read,
-) -> impl Iterator<Item = (PackInfoEntry, Box<[u8]>)> {
-    fastcdc::StreamCDC::new(data, CHUNK_MIN_SIZE, CHUNK_AVG_SIZE, CHUNK_MAX_SIZE).map(|chunk| {
-        let chunk = chunk.unwrap();
-        let id = blake3::hash(&chunk.data).into();
+/// Finds the compressor set up for a compression and dictionary, setting one up
+/// if there is none yet.
+fn cached_compressor<'a>(
+    compressors: &'a mut Vec<(Compression, Option<Hash>, Compressor<'static>)>,
+    compression: Compression,
+    level: i32,
+    dictionary: Option<(Hash, &[u8])>,
+) -> &'a mut Compressor<'static> {
+    let dictionary_id = dictionary.map(|(id, _)| id);
+    let position = compressors
+        .iter()
+        .position(|&(c, d, _)| c == compression && d == dictionary_id);
+
+    let position = position.unwrap_or_else(|| {
+        let compressor = compression.compressor(level, dictionary.map(|(_, data)| data));
+        compressors.push((compression, dictionary_id, compressor));
+        compressors.len() - 1
+    });
+
+    &mut compressors[position].2
+}
 
-        let (kind, size_compressed, data) = if chunk.data.len() < BLOB_COMPRESSION_THRESHOLD {
-            (BlobKind::Data, None, chunk.data)
+/// Lays out the blobs listed in a pack header the way [`Packer`] wrote them,
+/// back to back and each sealed on its own.
+pub fn index_pack_info(id: Hash, blobs: &[PackInfoEntry], cipher: Cipher) -> IndexPackInfo {
+    let mut cursor = 0;
+    let mut ies = Vec::new();
+
+    for blob in blobs {
+        let length = match blob.size_compressed {
+            Some(size) => size.get(),
+            None => blob.size_uncompressed,
+        };
+
+        let length_uncompressed = if blob.size_compressed.is_some() {
+            let size_uncompressed = NonZeroUsize::new(blob.size_uncompressed).unwrap();
+            Some(size_uncompressed)
         } else {
-            let compressed =
-                zstd::bulk::compress(&chunk.data, zstd::DEFAULT_COMPRESSION_LEVEL).unwrap();
-
-            if compressed.len() < chunk.data.len() {
-                (
-                    BlobKind::DataZstd3,
-                    Some(NonZeroUsize::new(compressed.len()).unwrap()),
-                    compressed,
-                )
-            } else {
-                (BlobKind::Data, None, chunk.data)
-            }
+            None
         };
 
-        let entry = PackInfoEntry {
-            id,
-            kind,
-            size_uncompressed: data.len(),
-            size_compressed,
+        let ib = IndexBlobInfo {
+            id: blob.id,
+            kind: blob.kind,
+            offset: cursor,
+            length,
+            length_uncompressed,
         };
 
-        (entry, data.into_boxed_slice())
+        cursor += length + cipher.overhead();
+        ies.push(ib)
+    }
+
+    IndexPackInfo { id, blobs: ies }
+}
+
+/// Sets up the chunker files are split into data blobs with, keyed with the
+/// chunker seed of the repository.
+pub fn chunker(params: &ChunkerParams, seed: &[u8; 32]) -> Box<dyn Chunker> {
+    let (min, avg, max) = (params.min_size, params.avg_size, params.max_size);
+    match params.algorithm {
+        ChunkerAlgorithm::FastCdc2020 => Box::new(
+            FastCdc2020::new(min, avg, max, params.normalization()).with_gear(Gear::permuted(seed)),
+        ),
+        ChunkerAlgorithm::FastCdc2016 => Box::new(
+            FastCdc2016::new(min, avg, max, params.normalization()).with_gear(Gear::permuted(seed)),
+        ),
+        ChunkerAlgorithm::Buzhash => Box::new(Buzhash::new(min, avg, max, seed)),
+        ChunkerAlgorithm::Fixed => Box::new(Fixed::new(avg)),
+    }
+}
+
+pub fn split_to_data_blobs<'a>(
+    data: &'a mut dyn Read,
+    chunker: &'a dyn Chunker,
+    compression: Compression,
+    dictionary: Option<(Hash, &'a [u8])>,
+    subkeys: &'a Subkeys,
+) -> impl Iterator<Item = (PackInfoEntry, Box<[u8]>)> + 'a {
+    StreamCDC::with_chunker(data, chunker).map(move |chunk| {
+        let chunk = chunk.unwrap();
+        let id = subkeys.blob_id(&chunk.data);
+        encode_blob(id, BlobClass::Data, chunk.data, compression, dictionary)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::unseal_blob;
+
+    #[test]
+    fn test_default_chunker_params_are_valid() {
+        ChunkerParams::default().validate();
+    }
+
+    #[test]
+    fn test_encode_blob_compression() {
+        let id = Hash::from(blake3::hash(b"blob"));
+        let text = b"all work and no play makes jack a dull boy\n".repeat(100);
+        let mut noise = vec![0; text.len()];
+        getrandom::fill(&mut noise).unwrap();
+
+        let (entry, _) = encode_blob(id, BlobClass::Tree, text.clone(), Compression::Auto, None);
+        assert_eq!(entry.kind, BlobKind::TreeZstd(3));
+        assert!(entry.size_compressed.unwrap().get() < text.len());
+
+        let (entry, _) = encode_blob(id, BlobClass::Data, text.clone(), Compression::Off, None);
+        assert_eq!(entry.kind, BlobKind::Data);
+
+        let (entry, data) =
+            encode_blob(id, BlobClass::Data, noise.clone(), Compression::Auto, None);
+        assert_eq!(entry.kind, BlobKind::Data);
+        assert_eq!(&*data, &noise[..]);
+    }
+
+    #[test]
+    fn test_packer_counts_blob_overhead() {
+        let mut packer = Packer::new(
+            Key { bytes: [7; 32] },
+            Cipher::ChaCha12Blake3,
+            PackSize::default(),
+        );
+
+        for i in 0..100u32 {
+            let data = i.to_le_bytes();
+            let id = Hash::from(blake3::hash(&data));
+            let (entry, data) =
+                encode_blob(id, BlobClass::Data, data.to_vec(), Compression::Auto, None);
+            packer.add_blob(entry, &data).unwrap();
+        }
+
+        let per_blob = packer.overhead() / packer.len();
+        assert_eq!(packer.size, 100 * 4 + packer.overhead());
+        assert!(per_blob > Cipher::ChaCha12Blake3.overhead() + 32);
+    }
+
+    #[test]
+    fn test_packer_full() {
+        let pack_size = PackSize {
+            target: 1024,
+            max: 2048,
+        };
+        let mut packer = Packer::new(Key { bytes: [7; 32] }, Cipher::ChaCha12Blake3, pack_size);
+        let blob = |size: usize| {
+            let data = vec![size as u8; size];
+            let id = Hash::from(blake3::hash(&data));
+            encode_blob(id, BlobClass::Data, data, Compression::Off, None)
+        };
+
+        let (entry, data) = blob(1500);
+        packer.add_blob(entry, &data).unwrap();
+        assert!(packer.should_pack());
+
+        let (entry, data) = blob(1000);
+        assert_eq!(packer.add_blob(entry, &data), Err(PackFull));
+        assert_eq!(packer.len(), 1);
+
+        // A blob larger than the maximum still fits in a pack of its own.
+        packer.finish();
+        let (entry, data) = blob(4096);
+        packer.add_blob(entry, &data).unwrap();
+        let (index, pack) = packer.finish();
+        assert_eq!(index.blobs.len(), 1);
+        assert!(pack.len() > pack_size.max);
+    }
+
+    #[test]
+    fn test_pack_blob_full() {
+        let pack_size = PackSize {
+            target: 1024,
+            max: 2048,
+        };
+        let mut packer = Packer::new(Key { bytes: [7; 32] }, Cipher::ChaCha12Blake3, pack_size);
+        let text = b"all work and no play makes jack a dull boy\n".repeat(25);
+        let id = Hash::from(blake3::hash(&text));
+
+        packer
+            .pack_blob(id, BlobClass::Data, &text, Compression::Off, None)
+            .unwrap();
+        let size = packer.buffer.len();
+
+        // Would fit compressed, but is turned away before compressing it.
+        assert!(
+            packer
+                .pack_blob(id, BlobClass::Data, &text, Compression::Auto, None)
+                .is_err()
+        );
+        assert_eq!((packer.len(), packer.buffer.len()), (1, size));
+
+        packer
+            .pack_blob(id, BlobClass::Data, &text[..200], Compression::Auto, None)
+            .unwrap();
+        assert_eq!(packer.len(), 2);
+    }
+
+    #[test]
+    fn test_pack_blob_matches_add_blob() {
+        let key = Key { bytes: [7; 32] };
+        let text = b"all work and no play makes jack a dull boy\n".repeat(100);
+        let mut noise = vec![0; 5000];
+        getrandom::fill(&mut noise).unwrap();
+        let samples = (0..200)
+            .map(|i| format!("{{\"name\": \"file{}.txt\", \"mode\": 33188}}", i).into_bytes())
+            .collect::<Vec<_>>();
+        let dictionary_data = train_dictionary(&samples).unwrap();
+        let dictionary = Some((
+            Hash::from(blake3::hash(&dictionary_data)),
+            &dictionary_data[..],
+        ));
+
+        let blobs = [
+            (BlobClass::Tree, text.clone(), None),
+            (BlobClass::Data, samples[7].repeat(3), dictionary),
+            (BlobClass::Data, noise.clone(), None),
+            (BlobClass::Data, b"tiny".to_vec(), None),
+        ];
+
+        let mut copied = Packer::new(key, Cipher::XChaCha20Poly1305, PackSize::default());
+        let mut direct = Packer::new(key, Cipher::XChaCha20Poly1305, PackSize::default());
+
+        for (class, data, dictionary) in blobs {
+            let id = Hash::from(blake3::hash(&data));
+            let (entry, encoded) =
+                encode_blob(id, class, data.clone(), Compression::Auto, dictionary);
+            copied.add_blob(entry, &encoded).unwrap();
+
+            let packed = direct
+                .pack_blob(id, class, &data, Compression::Auto, dictionary)
+                .unwrap();
+            assert_eq!(packed.kind, entry.kind);
+            assert_eq!(packed.size_compressed, entry.size_compressed);
+        }
+
+        assert_eq!(direct.size, copied.size);
+        let (copied_index, copied_pack) = copied.finish();
+        let (direct_index, direct_pack) = direct.finish();
+        assert_eq!(copied_pack.len(), direct_pack.len());
+
+        for (copied_blob, direct_blob) in copied_index.blobs.iter().zip(&direct_index.blobs) {
+            let sealed = |pack: &[u8], blob: &IndexBlobInfo| {
+                let start = blob.offset;
+                unseal_blob(
+                    &pack[start..start + blob.length + Cipher::XChaCha20Poly1305.overhead()],
+                    &key,
+                )
+            };
+            assert_eq!(
+                sealed(&copied_pack, copied_blob),
+                sealed(&direct_pack, direct_blob)
+            );
+        }
+    }
+
+    #[test]
+    fn test_blob_kind_encoding() {
+        let dictionary = Hash::from(blake3::hash(b"dictionary"));
+
+        for kind in [
+            BlobKind::Tree,
+            BlobKind::Data,
+            BlobKind::TreeZstd(19),
+            BlobKind::DataZstd(22),
+            BlobKind::TreeZstdDictionary(3, dictionary),
+            BlobKind::DataZstdDictionary(9, dictionary),
+        ] {
+            let encoded = rmp_serde::to_vec(&kind).unwrap();
+            assert_eq!(rmp_serde::from_slice::<BlobKind>(&encoded).unwrap(), kind);
+        }
+
+        let legacy = rmp_serde::to_vec(&3).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<BlobKind>(&legacy).unwrap(),
+            BlobKind::DataZstd(3)
+        );
+    }
+
+    #[test]
+    fn test_encode_blob_with_dictionary() {
+        let samples = (0..200)
+            .map(|i| {
+                format!(
+                    "{{\"name\": \"file{}.txt\", \"mode\": 33188, \"uid\": 1000}}",
+                    i
+                )
+                .into_bytes()
+            })
+            .collect::<Vec<_>>();
+        let dictionary = train_dictionary(&samples).unwrap();
+        let dictionary_id = Hash::from(blake3::hash(&dictionary));
+
+        let blob = b"{\"name\": \"file1000.txt\", \"mode\": 33188, \"uid\": 1000}".repeat(3);
+        let id = Hash::from(blake3::hash(&blob));
+        let (entry, data) = encode_blob(
+            id,
+            BlobClass::Tree,
+            blob.clone(),
+            Compression::Auto,
+            Some((dictionary_id, &dictionary)),
+        );
+
+        assert_eq!(entry.kind, BlobKind::TreeZstdDictionary(3, dictionary_id));
+        let decompressed = zstd::bulk::Decompressor::with_dictionary(&dictionary)
+            .unwrap()
+            .decompress(&data, blob.len())
+            .unwrap();
+        assert_eq!(decompressed, blob);
+    }
+
+    #[test]
+    #[should_panic(expected = "not in increasing order")]
+    fn test_chunker_params_out_of_order() {
+        ChunkerParams {
+            min_size: 64 * 1024,
+            avg_size: 32 * 1024,
+            max_size: 128 * 1024,
+            level: 1,
+            algorithm: ChunkerAlgorithm::FastCdc2020,
+        }
+        .validate();
+    }
+
+    #[test]
+    fn test_chunker_params_without_algorithm() {
+        let old = rmp_serde::to_vec(&(512 * 1024, 1024 * 1024, 2 * 1024 * 1024, 1)).unwrap();
+        let params: ChunkerParams = rmp_serde::from_slice(&old).unwrap();
+        assert_eq!(params, ChunkerParams::default());
+
+        let fixed = ChunkerParams {
+            algorithm: ChunkerAlgorithm::Fixed,
+            ..ChunkerParams::default()
+        };
+        let params: ChunkerParams =
+            rmp_serde::from_slice(&rmp_serde::to_vec(&fixed).unwrap()).unwrap();
+        assert_eq!(params, fixed);
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+/// Storage class of an object. Hot objects are everything needed to browse
+/// snapshots: the config, keys, indexes, snapshots, dictionaries and tree
+/// packs. Cold objects, the data packs, are only read for file contents, so
+/// they can be kept on slower and cheaper storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectClass {
+    Hot,
+    Cold,
+}
+
+/// Where a repository keeps its objects. Objects are addressed by their class
+/// and a path of a directory and a file name, like `index/<id>.index`.
+pub trait Backend {
+    fn exists(&self, class: ObjectClass, path: &str) -> bool;
+
+    fn read(&self, class: ObjectClass, path: &str) -> io::Result<Vec<u8>>;
+
+    /// Reads `length` bytes starting at `offset`, so single blobs can be read
+    /// out of a pack.
+    fn read_range(
+        &self,
+        class: ObjectClass,
+        path: &str,
+        offset: u64,
+        length: usize,
+    ) -> io::Result<Vec<u8>>;
+
+    /// The size of an object, found without reading it.
+    fn size(&self, class: ObjectClass, path: &str) -> io::Result<u64>;
+
+    /// Stores an object atomically, so it is either complete or absent.
+    fn write(&self, class: ObjectClass, path: &str, data: &[u8]);
+
+    /// Lists the names of the objects in a directory, sorted, or nothing if
+    /// the directory doesn't exist.
+    fn list(&self, class: ObjectClass, dir: &str) -> Vec<String>;
+
+    fn remove(&self, class: ObjectClass, path: &str);
+}
+
+/// Keeps objects on the local filesystem, each class below a root of its own.
+/// Both roots are usually the repository directory.
+pub struct LocalBackend {
+    hot: PathBuf,
+    cold: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(hot: &Path, cold: &Path) -> Self {
+        Self {
+            hot: hot.to_path_buf(),
+            cold: cold.to_path_buf(),
+        }
+    }
+
+    fn path(&self, class: ObjectClass, path: &str) -> PathBuf {
+        match class {
+            ObjectClass::Hot => self.hot.join(path),
+            ObjectClass::Cold => self.cold.join(path),
+        }
+    }
+}
+
+impl Backend for LocalBackend {
+    fn exists(&self, class: ObjectClass, path: &str) -> bool {
+        fs::exists(self.path(class, path)).unwrap()
+    }
+
+    fn read(&self, class: ObjectClass, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(class, path))
+    }
+
+    fn read_range(
+        &self,
+        class: ObjectClass,
+        path: &str,
+        offset: u64,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut file = fs::File::open(self.path(class, path))?;
+        let mut data = vec![0; length];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn size(&self, class: ObjectClass, path: &str) -> io::Result<u64> {
+        fs::metadata(self.path(class, path)).map(|metadata| metadata.len())
+    }
+
+    fn write(&self, class: ObjectClass, path: &str, data: &[u8]) {
+        let path = self.path(class, path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        let temporary = path.with_extension(TEMPORARY_EXTENSION);
+        fs::write(&temporary, data).unwrap();
+        fs::rename(&temporary, path).unwrap();
+    }
+
+    fn list(&self, class: ObjectClass, dir: &str) -> Vec<String> {
+        let entries = match fs::read_dir(self.path(class, dir)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(err) => panic!("failed to list {:?}: {}", dir, err),
+        };
+
+        let mut names = entries
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        names.sort();
+        names
+    }
+
+    fn remove(&self, class: ObjectClass, path: &str) {
+        fs::remove_file(self.path(class, path)).unwrap();
+    }
+}
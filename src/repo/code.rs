@@ -1,9 +1,14 @@
 #![allow(dead_code)]
 
+use std::fmt;
+use std::str::FromStr;
+
 use chacha20::ChaCha12;
 use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20poly1305::aead::{Aead, AeadInPlace, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 
-use crate::repo::types::Key;
+use crate::repo::types::{Cipher, Key};
 
 const ENCRYPTION_CONTEXT: &str = "encryption";
 const AUTHENTICATION_CONTEXT: &str = "authentication";
@@ -14,6 +19,71 @@ const NONCE_SIZE: usize = 32;
 const CIPHER_NONCE_SIZE: usize = 12;
 const MAC_SIZE: usize = 32;
 
+const XNONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// Sealed blobs other than legacy ones start with the envelope version and the
+/// id of the algorithm they were sealed with.
+const ENVELOPE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 2;
+
+const ALGORITHM_CHACHA12_BLAKE3: u8 = 1;
+const ALGORITHM_XCHACHA20_POLY1305: u8 = 2;
+
+impl Cipher {
+    /// Number of bytes a blob sealed with this cipher is larger than its
+    /// plaintext.
+    pub fn overhead(self) -> usize {
+        match self {
+            Cipher::Legacy => NONCE_SIZE + MAC_SIZE,
+            Cipher::ChaCha12Blake3 => HEADER_SIZE + NONCE_SIZE + MAC_SIZE,
+            Cipher::XChaCha20Poly1305 => HEADER_SIZE + XNONCE_SIZE + TAG_SIZE,
+        }
+    }
+
+    fn nonce_size(self) -> usize {
+        match self {
+            Cipher::Legacy | Cipher::ChaCha12Blake3 => NONCE_SIZE,
+            Cipher::XChaCha20Poly1305 => XNONCE_SIZE,
+        }
+    }
+
+    fn header(self) -> Option<[u8; HEADER_SIZE]> {
+        match self {
+            Cipher::Legacy => None,
+            Cipher::ChaCha12Blake3 => Some([ENVELOPE_VERSION, ALGORITHM_CHACHA12_BLAKE3]),
+            Cipher::XChaCha20Poly1305 => Some([ENVELOPE_VERSION, ALGORITHM_XCHACHA20_POLY1305]),
+        }
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "chacha12-blake3" => Ok(Cipher::ChaCha12Blake3),
+            "xchacha20-poly1305" => Ok(Cipher::XChaCha20Poly1305),
+            _ => Err(format!(
+                "unknown cipher {:?}, expected chacha12-blake3 or xchacha20-poly1305",
+                name
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cipher::Legacy => "legacy",
+            Cipher::ChaCha12Blake3 => "chacha12-blake3",
+            Cipher::XChaCha20Poly1305 => "xchacha20-poly1305",
+        };
+
+        f.write_str(name)
+    }
+}
+
 fn derive_encryption_key(key: &Key) -> [u8; 32] {
     blake3::derive_key(ENCRYPTION_CONTEXT, &key.bytes)
 }
@@ -31,49 +101,218 @@ fn derive_cipher_nonce(nonce: &[u8; NONCE_SIZE]) -> [u8; 12] {
     cipher_nonce
 }
 
-pub fn seal_blob(plain: &[u8], key: &Key) -> Vec<u8> {
-    let mut nonce = [0u8; 32];
+pub fn seal_blob(plain: &[u8], key: &Key, cipher: Cipher) -> Vec<u8> {
+    let mut nonce = vec![0u8; cipher.nonce_size()];
     getrandom::fill(&mut nonce).unwrap();
+    seal_with_nonce(plain, key, cipher, &nonce)
+}
+
+fn seal_with_nonce(plain: &[u8], key: &Key, cipher: Cipher, nonce: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(plain.len() + cipher.overhead());
+    buf.extend(cipher.header().iter().flatten());
+    buf.extend_from_slice(nonce);
+    buf.extend_from_slice(plain);
 
-    let e_key = derive_encryption_key(key);
-    let a_key = derive_authentication_key(key, &nonce);
-    let cipher_nonce = derive_cipher_nonce(&nonce);
+    finish_seal(&mut buf, 0, key, cipher);
+    buf
+}
 
-    let mut cipher = ChaCha12::new(&e_key.into(), &cipher_nonce.into());
+/// Starts sealing a blob in place at the end of `buf` by appending the header
+/// and a fresh nonce, and returns where the sealed blob starts. Append the
+/// plaintext next and then call [`finish_seal`], which saves the copies
+/// [`seal_blob`] makes when blobs are sealed straight into a pack.
+pub fn begin_seal(buf: &mut Vec<u8>, cipher: Cipher) -> usize {
+    let start = buf.len();
+    buf.extend(cipher.header().iter().flatten());
 
-    let mut buf = Vec::with_capacity(NONCE_SIZE + plain.len() + MAC_SIZE);
-    buf.extend_from_slice(&nonce);
-    buf.extend_from_slice(plain);
-    cipher.apply_keystream(buf.as_mut_slice());
+    let nonce = buf.len();
+    buf.resize(nonce + cipher.nonce_size(), 0);
+    getrandom::fill(&mut buf[nonce..]).unwrap();
+    start
+}
 
-    let mac = blake3::keyed_hash(&a_key, &buf);
-    buf.extend_from_slice(mac.as_bytes());
+/// Encrypts the plaintext following the header and nonce at `start` in place
+/// and appends the authentication tag.
+pub fn finish_seal(buf: &mut Vec<u8>, start: usize, key: &Key, cipher: Cipher) {
+    let header_size = cipher.header().map_or(0, |header| header.len());
+    let body = start + header_size + cipher.nonce_size();
 
-    buf
+    match cipher {
+        Cipher::Legacy | Cipher::ChaCha12Blake3 => {
+            let nonce: &[u8; NONCE_SIZE] = buf[start + header_size..body].try_into().unwrap();
+            let e_key = derive_encryption_key(key);
+            let a_key = derive_authentication_key(key, nonce);
+            let cipher_nonce = derive_cipher_nonce(nonce);
+
+            let mut cipher = ChaCha12::new(&e_key.into(), &cipher_nonce.into());
+            cipher.apply_keystream(&mut buf[body..]);
+
+            // The header, if any, is covered by the MAC along with the rest.
+            let mac = blake3::keyed_hash(&a_key, &buf[start..]);
+            buf.extend_from_slice(mac.as_bytes());
+        }
+        Cipher::XChaCha20Poly1305 => {
+            let aead = XChaCha20Poly1305::new(&derive_encryption_key(key).into());
+            let (prefix, plain) = buf[start..].split_at_mut(body - start);
+            let (header, nonce) = prefix.split_at(header_size);
+
+            let tag = aead
+                .encrypt_in_place_detached(XNonce::from_slice(nonce), header, plain)
+                .unwrap();
+            buf.extend_from_slice(&tag);
+        }
+    }
 }
 
 pub fn unseal_blob(data: &[u8], key: &Key) -> Vec<u8> {
-    let data_nonce = &data[..NONCE_SIZE];
-    let data_ciphertext = &data[(data.len() - MAC_SIZE)..MAC_SIZE];
-    let data_mac = blake3::Hash::from_slice(&data[(data.len() - MAC_SIZE)..]).unwrap();
+    if data.len() < Cipher::XChaCha20Poly1305.overhead() {
+        panic!("sealed blob is truncated");
+    }
 
-    let mut nonce = [0u8; 32];
-    nonce.copy_from_slice(data_nonce);
+    try_unseal_blob(data, key).expect("sealed blob failed authentication")
+}
 
-    let e_key = derive_encryption_key(key);
-    let a_key = derive_authentication_key(key, &nonce);
-    let cipher_nonce = derive_cipher_nonce(&nonce);
+/// Like `unseal_blob`, but returns `None` if the blob is truncated or fails
+/// authentication, such as when trying a key which may not be the right one.
+///
+/// Legacy blobs have no header and start with a random nonce, which may look
+/// like a header by chance, so a blob failing to open as a versioned envelope
+/// is retried as a legacy one.
+pub fn try_unseal_blob(data: &[u8], key: &Key) -> Option<Vec<u8>> {
+    let cipher = match data {
+        [ENVELOPE_VERSION, ALGORITHM_CHACHA12_BLAKE3, ..] => Some(Cipher::ChaCha12Blake3),
+        [ENVELOPE_VERSION, ALGORITHM_XCHACHA20_POLY1305, ..] => Some(Cipher::XChaCha20Poly1305),
+        _ => None,
+    };
 
-    let mut cipher = ChaCha12::new(&e_key.into(), &cipher_nonce.into());
+    cipher
+        .and_then(|cipher| open(data, key, cipher))
+        .or_else(|| open(data, key, Cipher::Legacy))
+}
 
-    let mac = blake3::keyed_hash(&a_key, data);
-    if mac != data_mac {
-        panic!();
+fn open(data: &[u8], key: &Key, cipher: Cipher) -> Option<Vec<u8>> {
+    if data.len() < cipher.overhead() {
+        return None;
     }
 
-    let mut buf = Vec::with_capacity(data.len() - NONCE_SIZE - MAC_SIZE);
-    buf.extend_from_slice(data_ciphertext);
-    cipher.apply_keystream(buf.as_mut_slice());
+    let header_size = cipher.header().map_or(0, |header| header.len());
+    let (header, body) = data.split_at(header_size);
 
-    buf
+    match cipher {
+        Cipher::Legacy | Cipher::ChaCha12Blake3 => {
+            let mac_start = data.len() - MAC_SIZE;
+            let nonce: &[u8; NONCE_SIZE] = body[..NONCE_SIZE].try_into().unwrap();
+            let data_mac = blake3::Hash::from_slice(&data[mac_start..]).unwrap();
+
+            let e_key = derive_encryption_key(key);
+            let a_key = derive_authentication_key(key, nonce);
+            let cipher_nonce = derive_cipher_nonce(nonce);
+
+            let mac = blake3::keyed_hash(&a_key, &data[..mac_start]);
+            if mac != data_mac {
+                return None;
+            }
+
+            let mut cipher = ChaCha12::new(&e_key.into(), &cipher_nonce.into());
+            let mut buf = data[header_size + NONCE_SIZE..mac_start].to_vec();
+            cipher.apply_keystream(&mut buf);
+            Some(buf)
+        }
+        Cipher::XChaCha20Poly1305 => {
+            let aead = XChaCha20Poly1305::new(&derive_encryption_key(key).into());
+            let (nonce, sealed) = body.split_at(XNONCE_SIZE);
+            let payload = Payload {
+                msg: sealed,
+                aad: header,
+            };
+
+            aead.decrypt(XNonce::from_slice(nonce), payload).ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_roundtrip() {
+        let key = Key { bytes: [7; 32] };
+        let plain = b"the quick brown fox jumps over the lazy dog";
+
+        for cipher in [
+            Cipher::Legacy,
+            Cipher::ChaCha12Blake3,
+            Cipher::XChaCha20Poly1305,
+        ] {
+            let sealed = seal_blob(plain, &key, cipher);
+            assert_eq!(sealed.len(), plain.len() + cipher.overhead());
+            assert!(!sealed.windows(plain.len()).any(|window| window == plain));
+            assert_eq!(unseal_blob(&sealed, &key), plain);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "failed authentication")]
+    fn test_unseal_tampered() {
+        let key = Key { bytes: [7; 32] };
+        let mut sealed = seal_blob(b"payload", &key, Cipher::Legacy);
+        sealed[NONCE_SIZE] ^= 1;
+        unseal_blob(&sealed, &key);
+    }
+
+    #[test]
+    fn test_unseal_tampered_header() {
+        let key = Key { bytes: [7; 32] };
+        let mut sealed = seal_blob(b"payload", &key, Cipher::XChaCha20Poly1305);
+        sealed[1] = ALGORITHM_CHACHA12_BLAKE3;
+        assert!(try_unseal_blob(&sealed, &key).is_none());
+    }
+
+    #[test]
+    fn test_known_answers() {
+        let key = Key { bytes: [0x42; 32] };
+        let plain = b"casb known answer";
+        let vectors = [
+            (Cipher::Legacy, [0x11; NONCE_SIZE].as_slice(), LEGACY_VECTOR),
+            (
+                Cipher::ChaCha12Blake3,
+                &[0x11; NONCE_SIZE],
+                CHACHA12_BLAKE3_VECTOR,
+            ),
+            (
+                Cipher::XChaCha20Poly1305,
+                &[0x11; XNONCE_SIZE],
+                XCHACHA20_POLY1305_VECTOR,
+            ),
+        ];
+
+        for (cipher, nonce, expected) in vectors {
+            let sealed = seal_with_nonce(plain, &key, cipher, nonce);
+            assert_eq!(hex::encode(&sealed), expected, "{}", cipher);
+            assert_eq!(unseal_blob(&hex::decode(expected).unwrap(), &key), plain);
+        }
+    }
+
+    /// The AEAD test vector from draft-irtf-cfrg-xchacha, section A.3.1.
+    #[test]
+    fn test_xchacha20_poly1305_draft_vector() {
+        let key = hex::decode("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f")
+            .unwrap();
+        let nonce = hex::decode("404142434445464748494a4b4c4d4e4f5051525354555657").unwrap();
+        let aad = hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+
+        let aead = XChaCha20Poly1305::new_from_slice(&key).unwrap();
+        let tag = aead
+            .encrypt_in_place_detached(XNonce::from_slice(&nonce), &aad, &mut data)
+            .unwrap();
+        assert_eq!(hex::encode(&data), XCHACHA_DRAFT_CIPHERTEXT);
+        assert_eq!(hex::encode(tag), "c0875924c1c7987947deafd8780acf49");
+    }
+
+    const XCHACHA_DRAFT_CIPHERTEXT: &str = "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b4522f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff921f9664c97637da9768812f615c68b13b52e";
+    const LEGACY_VECTOR: &str = "1111111111111111111111111111111111111111111111111111111111111111267d085aa8efff982f2cf27afe7fde4f1c7fb99aa29ee0814d07f565b56130915cc61b3ef5e69e0c3c984517cacfb86bee";
+    const CHACHA12_BLAKE3_VECTOR: &str = "01011111111111111111111111111111111111111111111111111111111111111111267d085aa8efff982f2cf27afe7fde4f1c3b1eab2218b27bb5057881b71735c51f3609d32afe3dc70432cc435550af5f9b";
+    const XCHACHA20_POLY1305_VECTOR: &str = "010211111111111111111111111111111111111111111111111155d78119ba729ab50f1245cfc7312f24c3d1b5d875c8b3556c24a216a6568cb590";
 }
@@ -1,9 +1,12 @@
-use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
-#[serde(transparent)]
+use serde::de::{Error, SeqAccess, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A 32 byte object id. Stored as raw bytes in the repository, but shown as
+/// hex by human readable formats such as JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Hash {
-    #[serde(with = "serde_bytes")]
     pub bytes: [u8; 32],
 }
 
@@ -12,6 +15,12 @@ impl Hash {
     pub fn to_hex(&self) -> String {
         hex::encode(self.bytes)
     }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(hex, &mut bytes).ok()?;
+        Some(Hash { bytes })
+    }
 }
 
 impl From<blake3::Hash> for Hash {
@@ -21,3 +30,54 @@ impl From<blake3::Hash> for Hash {
         }
     }
 }
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_hex())
+        } else {
+            serde_bytes::serialize(&self.bytes, serializer)
+        }
+    }
+}
+
+/// Accepts both the hex and the byte form regardless of what the format claims
+/// to be, since serde buffers flattened fields as if they were human readable.
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(HashVisitor)
+    }
+}
+
+struct HashVisitor;
+
+impl<'de> Visitor<'de> for HashVisitor {
+    type Value = Hash;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("32 bytes or 64 hex digits")
+    }
+
+    fn visit_str<E: Error>(self, hex: &str) -> Result<Hash, E> {
+        Hash::from_hex(hex).ok_or_else(|| E::invalid_value(Unexpected::Str(hex), &self))
+    }
+
+    fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Hash, E> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| E::invalid_length(bytes.len(), &self))?;
+
+        Ok(Hash { bytes })
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Hash, A::Error> {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+        }
+
+        Ok(Hash { bytes })
+    }
+}
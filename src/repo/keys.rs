@@ -0,0 +1,219 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use argon2::{Algorithm, Argon2, Version};
+
+use crate::repo::code::{seal_blob, try_unseal_blob};
+use crate::repo::{Cipher, Hash, Kdf, Key, Recipe};
+
+const SALT_SIZE: usize = 32;
+
+/// Most memory, in KiB, calibration will ask Argon2id to use before it starts
+/// adding passes instead.
+const CALIBRATION_MAX_MEMORY: u32 = 1024 * 1024;
+
+impl Key {
+    /// Generates a new random master key.
+    pub fn generate() -> Self {
+        let mut bytes = [0; 32];
+        getrandom::fill(&mut bytes).unwrap();
+        Key { bytes }
+    }
+}
+
+/// Keys derived from the master key, so every kind of object is sealed with a
+/// key of its own and blob ids are keyed hashes which don't reveal the hash of
+/// the plaintext.
+#[derive(Debug, Clone, Copy)]
+pub struct Subkeys {
+    pub data: Key,
+    pub tree: Key,
+    pub index: Key,
+    pub snapshot: Key,
+    pub dictionary: Key,
+    pub id: Key,
+}
+
+impl Subkeys {
+    pub fn derive(master: &Key) -> Self {
+        let derive = |context| Key {
+            bytes: blake3::derive_key(context, &master.bytes),
+        };
+
+        Subkeys {
+            data: derive("casb data blob key"),
+            tree: derive("casb tree blob key"),
+            index: derive("casb index key"),
+            snapshot: derive("casb snapshot key"),
+            dictionary: derive("casb dictionary key"),
+            id: derive("casb blob id key"),
+        }
+    }
+
+    pub fn blob_id(&self, data: &[u8]) -> Hash {
+        blake3::keyed_hash(&self.id.bytes, data).into()
+    }
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::Argon2id {
+            m: 64 * 1024,
+            t: 3,
+            p: 4,
+        }
+    }
+}
+
+impl fmt::Display for Kdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kdf::Scrypt { n, r, p } => write!(f, "scrypt N={} r={} p={}", n, r, p),
+            Kdf::Argon2id { m, t, p } => write!(f, "argon2id m={} t={} p={}", m, t, p),
+        }
+    }
+}
+
+impl Kdf {
+    /// Stretches a password into a key which wraps the master key, or fails if
+    /// the parameters are out of range. Scrypt's N must be a power of two
+    /// greater than one, as only its logarithm is used.
+    pub fn derive(&self, password: &[u8], salt: &[u8]) -> Result<Key, String> {
+        let mut bytes = [0; 32];
+
+        match *self {
+            Kdf::Scrypt { n, r, p } => {
+                let log_n = u32::try_from(n)
+                    .ok()
+                    .filter(|&n| n > 1 && n.is_power_of_two())
+                    .ok_or_else(|| format!("scrypt N={} is not a power of two above 1", n))?
+                    .ilog2();
+                let (r, p) = (
+                    u32::try_from(r).map_err(|_| format!("invalid scrypt r={}", r))?,
+                    u32::try_from(p).map_err(|_| format!("invalid scrypt p={}", p))?,
+                );
+                let params = scrypt::Params::new(log_n as u8, r, p, bytes.len())
+                    .map_err(|err| format!("invalid scrypt parameters: {}", err))?;
+                scrypt::scrypt(password, salt, &params, &mut bytes).unwrap();
+            }
+            Kdf::Argon2id { m, t, p } => {
+                let params = argon2::Params::new(m, t, p, Some(bytes.len()))
+                    .map_err(|err| format!("invalid argon2id parameters: {}", err))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password, salt, &mut bytes)
+                    .unwrap();
+            }
+        }
+
+        Ok(Key { bytes })
+    }
+
+    /// Picks Argon2id parameters which take at least `target` to derive a key
+    /// on this machine, doubling the memory cost and then the number of passes
+    /// until the target is reached.
+    pub fn calibrate(target: Duration) -> Self {
+        let (mut m, mut t) = (8 * 1024, 1);
+
+        loop {
+            let kdf = Kdf::Argon2id { m, t, p: 1 };
+            let start = Instant::now();
+            kdf.derive(b"calibration", &[0; SALT_SIZE]).unwrap();
+
+            if start.elapsed() >= target {
+                return kdf;
+            }
+
+            if m < CALIBRATION_MAX_MEMORY {
+                m *= 2;
+            } else {
+                t += 1;
+            }
+        }
+    }
+}
+
+impl Recipe {
+    /// Wraps the master key with a key derived from a password, or fails if
+    /// the key derivation parameters are out of range.
+    pub fn new(
+        master: &Key,
+        password: &[u8],
+        kdf: Kdf,
+        hostname: String,
+        username: String,
+    ) -> Result<Self, String> {
+        let mut salt = vec![0; SALT_SIZE];
+        getrandom::fill(&mut salt).unwrap();
+
+        let wrapping = kdf.derive(password, &salt)?;
+
+        Ok(Recipe {
+            hostname,
+            username,
+            kdf,
+            created: jiff::Timestamp::now().as_second(),
+            data: seal_blob(&master.bytes, &wrapping, Cipher::ChaCha12Blake3),
+            salt,
+        })
+    }
+
+    /// Recovers the master key, `None` if the password is wrong, or fails if
+    /// the key file records key derivation parameters out of range.
+    pub fn unwrap_key(&self, password: &[u8]) -> Result<Option<Key>, String> {
+        let wrapping = self.kdf.derive(password, &self.salt)?;
+        let Some(bytes) = try_unseal_blob(&self.data, &wrapping) else {
+            return Ok(None);
+        };
+
+        Ok(bytes.try_into().ok().map(|bytes| Key { bytes }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recipe_unwrap() {
+        let master = Key::generate();
+        let kdf = Kdf::Scrypt { n: 16, r: 8, p: 1 };
+        let recipe = Recipe::new(&master, b"hunter2", kdf, String::new(), String::new()).unwrap();
+
+        let unwrap = |password| recipe.unwrap_key(password).unwrap();
+        assert_eq!(unwrap(b"hunter2").unwrap().bytes, master.bytes);
+        assert!(unwrap(b"hunter3").is_none());
+    }
+
+    #[test]
+    fn test_argon2id_unwrap() {
+        let master = Key::generate();
+        let kdf = Kdf::Argon2id { m: 64, t: 1, p: 1 };
+        let recipe = Recipe::new(&master, b"hunter2", kdf, String::new(), String::new()).unwrap();
+
+        let unwrap = |password| recipe.unwrap_key(password).unwrap();
+        assert_eq!(unwrap(b"hunter2").unwrap().bytes, master.bytes);
+        assert!(unwrap(b"hunter3").is_none());
+    }
+
+    #[test]
+    fn test_scrypt_n_must_be_a_power_of_two() {
+        let master = Key::generate();
+        for n in [0, 1, 100_000, -16] {
+            let kdf = Kdf::Scrypt { n, r: 8, p: 1 };
+            assert!(Recipe::new(&master, b"hunter2", kdf, String::new(), String::new()).is_err());
+        }
+
+        // A key file recording such an N fails to unlock rather than being
+        // derived with another one.
+        let mut recipe = Recipe::new(
+            &master,
+            b"hunter2",
+            Kdf::Scrypt { n: 16, r: 8, p: 1 },
+            String::new(),
+            String::new(),
+        )
+        .unwrap();
+        recipe.kdf = Kdf::Scrypt { n: 24, r: 8, p: 1 };
+        assert!(recipe.unwrap_key(b"hunter2").is_err());
+    }
+}
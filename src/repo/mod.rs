@@ -1,10 +1,13 @@
+mod backend;
 mod code;
 mod hash;
+mod keys;
+mod storage;
 mod types;
 
 #[allow(unused_imports)]
 #[rustfmt::skip]
-pub use self::{hash::Hash,code::{seal_blob,unseal_blob},types::{
-    BlobKind, Config, Index, IndexBlobInfo, IndexPackInfo, Kdf, Key, Node, NodeKind, PackInfo,
+pub use self::{backend::{Backend,LocalBackend,ObjectClass},hash::Hash,code::{begin_seal,finish_seal,seal_blob,unseal_blob},keys::Subkeys,storage::{BlobLocation,ContentReader,Repository},types::{
+    BlobClass, BlobKind, ChunkerAlgorithm, ChunkerParams, Cipher, Config, Dictionary, Index, IndexBlobInfo, IndexPackInfo, Kdf, Key, Node, NodeKind, PackInfo, PackSize,
     PackInfoEntry, Recipe, RepositoryVersion, Snapshot, Tree, UnpackedEncoding,
 }};
@@ -0,0 +1,874 @@
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::pack;
+use crate::repo::{
+    Backend, BlobClass, BlobKind, ChunkerParams, Cipher, Config, Dictionary, Hash, Index,
+    IndexBlobInfo, IndexPackInfo, Key, LocalBackend, ObjectClass, PackInfo, PackSize, Recipe,
+    RepositoryVersion, Snapshot, Subkeys, Tree, seal_blob, unseal_blob,
+};
+
+const CONFIG_FILE: &str = "config";
+
+const DATA_DIR: &str = "data";
+const TREE_DIR: &str = "tree";
+const INDEX_DIR: &str = "index";
+const SNAPSHOT_DIR: &str = "snapshot";
+const KEY_DIR: &str = "keys";
+const DICTIONARY_DIR: &str = "dictionary";
+const HEADER_DIR: &str = "header";
+
+const PACK_EXTENSION: &str = "pack";
+const INDEX_EXTENSION: &str = "index";
+const SNAPSHOT_EXTENSION: &str = "snapshot";
+const KEY_EXTENSION: &str = "key";
+const DICTIONARY_EXTENSION: &str = "dict";
+const HEADER_EXTENSION: &str = "header";
+
+pub struct Repository {
+    backend: Box<dyn Backend>,
+    key: Key,
+    subkeys: Subkeys,
+    key_id: Option<Hash>,
+    config: Config,
+    upgraded: Cell<bool>,
+    blobs: OnceCell<HashMap<Hash, BlobLocation>>,
+    dictionaries: RefCell<HashMap<Hash, Vec<u8>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BlobLocation {
+    pub pack: Hash,
+    pub info: IndexBlobInfo,
+}
+
+impl BlobLocation {
+    pub fn size(&self) -> usize {
+        match self.info.length_uncompressed {
+            Some(size) => size.get(),
+            None => self.info.length,
+        }
+    }
+}
+
+impl Repository {
+    pub fn init(
+        root: &Path,
+        key: Key,
+        cipher: Cipher,
+        chunker: ChunkerParams,
+        pack_size: PackSize,
+        cold_storage: Option<PathBuf>,
+    ) -> Self {
+        chunker.validate();
+        pack_size.validate();
+
+        let backend = LocalBackend::new(root, root);
+        if backend.exists(ObjectClass::Hot, CONFIG_FILE) {
+            panic!("repository at {:?} already exists", root);
+        }
+
+        let mut chunker_seed = [0; 32];
+        getrandom::fill(&mut chunker_seed).unwrap();
+
+        let config = Config {
+            version: RepositoryVersion::V4,
+            id: Uuid::new_v4(),
+            chunker_seed,
+            cipher,
+            chunker,
+            pack_size,
+            cold_storage,
+        };
+
+        let data = seal_blob(&rmp_serde::to_vec(&config).unwrap(), &key, cipher);
+        backend.write(ObjectClass::Hot, CONFIG_FILE, &data);
+
+        Self::open(root, key)
+    }
+
+    /// Opens a repository on the local filesystem, with its data packs in the
+    /// cold storage directory if it has one.
+    pub fn open(root: &Path, key: Key) -> Self {
+        let backend = LocalBackend::new(root, root);
+        if !backend.exists(ObjectClass::Hot, CONFIG_FILE) {
+            panic!("no repository at {:?}", root);
+        }
+
+        let repository = Self::with_backend(Box::new(backend), key);
+        match &repository.config.cold_storage {
+            Some(cold) => Self {
+                backend: Box::new(LocalBackend::new(root, &root.join(cold))),
+                ..repository
+            },
+            None => repository,
+        }
+    }
+
+    pub fn with_backend(backend: Box<dyn Backend>, key: Key) -> Self {
+        let data = backend
+            .read(ObjectClass::Hot, CONFIG_FILE)
+            .expect("repository config is missing");
+        let config: Config = rmp_serde::from_slice(&unseal_blob(&data, &key)).unwrap();
+
+        if !matches!(
+            config.version,
+            RepositoryVersion::V2 | RepositoryVersion::V3 | RepositoryVersion::V4
+        ) {
+            panic!(
+                "repository format version {} is not supported",
+                i32::from(config.version)
+            );
+        }
+
+        config.chunker.validate();
+        config.pack_size.validate();
+
+        Self {
+            backend,
+            key,
+            subkeys: Subkeys::derive(&key),
+            key_id: None,
+            config,
+            upgraded: Cell::new(false),
+            blobs: OnceCell::new(),
+            dictionaries: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Opens a repository with the first key file the password unlocks.
+    pub fn unlock(root: &Path, password: &[u8]) -> Self {
+        let backend = LocalBackend::new(root, root);
+        if !backend.exists(ObjectClass::Hot, CONFIG_FILE) {
+            panic!("no repository at {:?}", root);
+        }
+
+        for path in list_objects(&backend, ObjectClass::Hot, KEY_DIR, KEY_EXTENSION) {
+            let (id, recipe) = read_key(&backend, &path);
+            let key = match recipe.unwrap_key(password) {
+                Ok(key) => key,
+                Err(err) => {
+                    log::warn!("skipping key {}: {}", &id.to_hex()[..8], err);
+                    continue;
+                }
+            };
+
+            if let Some(key) = key {
+                let mut repository = Self::open(root, key);
+                repository.key_id = Some(id);
+                return repository;
+            }
+        }
+
+        panic!("wrong password or no matching key in {:?}", root);
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.config.cipher
+    }
+
+    pub fn subkeys(&self) -> &Subkeys {
+        &self.subkeys
+    }
+
+    /// The key file used to unlock the repository, if any.
+    pub fn key_id(&self) -> Option<Hash> {
+        self.key_id
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The key blobs of a class, and the headers of the packs holding them,
+    /// are sealed with.
+    pub fn pack_key(&self, class: BlobClass) -> &Key {
+        match class {
+            BlobClass::Tree => &self.subkeys.tree,
+            BlobClass::Data => &self.subkeys.data,
+        }
+    }
+
+    pub fn indexes(&self) -> Vec<Index> {
+        list_objects(&*self.backend, ObjectClass::Hot, INDEX_DIR, INDEX_EXTENSION)
+            .into_iter()
+            .map(|path| self.read_object(&path, &self.subkeys.index).1)
+            .collect()
+    }
+
+    /// Resolves a unique prefix of an index id and reads that index.
+    pub fn find_index(&self, query: &str) -> (Hash, Index) {
+        let path = single_match(
+            self.matching_objects(ObjectClass::Hot, INDEX_DIR, INDEX_EXTENSION, query),
+            INDEX_EXTENSION,
+            query,
+        );
+        self.read_object(&path, &self.subkeys.index)
+    }
+
+    /// Marks a repository of an earlier version as version 4 before the first
+    /// object holding paths, which are encoded the version 4 way, is written
+    /// to it.
+    fn upgrade(&self) {
+        if matches!(self.config.version, RepositoryVersion::V4) || self.upgraded.replace(true) {
+            return;
+        }
+
+        let config = Config {
+            version: RepositoryVersion::V4,
+            ..self.config.clone()
+        };
+        let data = seal_blob(
+            &rmp_serde::to_vec(&config).unwrap(),
+            &self.key,
+            self.cipher(),
+        );
+        self.backend.write(ObjectClass::Hot, CONFIG_FILE, &data);
+    }
+
+    /// Stores a pack of blobs of one class, in the storage class of its packs.
+    /// Cold packs also get a hot copy of their header, so that packs left
+    /// behind by interrupted backups can be recovered without reading them.
+    pub fn write_pack(&self, class: BlobClass, id: Hash, data: &[u8]) {
+        let (object_class, dir) = pack_location(class);
+        let path = object_path(dir, &id, PACK_EXTENSION);
+
+        if self.backend.exists(object_class, &path) {
+            panic!("pack {} already exists", id.to_hex());
+        }
+
+        if class == BlobClass::Tree {
+            self.upgrade();
+        }
+
+        if object_class == ObjectClass::Cold {
+            let len_start = data.len() - 4;
+            let header_len = u32::from_le_bytes(data[len_start..].try_into().unwrap()) as usize;
+            self.backend.write(
+                ObjectClass::Hot,
+                &object_path(HEADER_DIR, &id, HEADER_EXTENSION),
+                &data[len_start - header_len..],
+            );
+        }
+
+        self.backend.write(object_class, &path, data);
+    }
+
+    pub fn write_index(&self, index: &Index) -> Hash {
+        self.write_object(INDEX_DIR, INDEX_EXTENSION, &self.subkeys.index, index)
+    }
+
+    pub fn write_snapshot(&self, snapshot: &Snapshot) -> Hash {
+        self.upgrade();
+        self.write_object(
+            SNAPSHOT_DIR,
+            SNAPSHOT_EXTENSION,
+            &self.subkeys.snapshot,
+            snapshot,
+        )
+    }
+
+    /// Seals and stores a standalone object, named by the hash of its sealed
+    /// contents. Standalone objects are all needed to browse snapshots, so
+    /// they are hot.
+    fn write_object<T: Serialize>(&self, dir: &str, extension: &str, key: &Key, value: &T) -> Hash {
+        let data = seal_blob(&rmp_serde::to_vec(value).unwrap(), key, self.cipher());
+        let id = Hash::from(blake3::hash(&data));
+
+        self.backend
+            .write(ObjectClass::Hot, &object_path(dir, &id, extension), &data);
+        id
+    }
+
+    fn read_object<T: DeserializeOwned>(&self, path: &str, key: &Key) -> (Hash, T) {
+        let data = self.backend.read(ObjectClass::Hot, path).unwrap();
+        let id = Hash::from(blake3::hash(&data));
+        let plain = unseal_blob(&data, key);
+        (id, rmp_serde::from_slice(&plain).unwrap())
+    }
+
+    /// Stores a key file. Key files can't be sealed with the master key they
+    /// protect, so they are kept as plain json.
+    pub fn write_key(&self, recipe: &Recipe) -> Hash {
+        let data = serde_json::to_vec(recipe).unwrap();
+        let id = Hash::from(blake3::hash(&data));
+
+        self.backend.write(
+            ObjectClass::Hot,
+            &object_path(KEY_DIR, &id, KEY_EXTENSION),
+            &data,
+        );
+        id
+    }
+
+    pub fn keys(&self) -> Vec<(Hash, Recipe)> {
+        list_objects(&*self.backend, ObjectClass::Hot, KEY_DIR, KEY_EXTENSION)
+            .into_iter()
+            .map(|path| read_key(&*self.backend, &path))
+            .collect()
+    }
+
+    /// Resolves a unique prefix of a key id and reads that key file.
+    pub fn find_key(&self, query: &str) -> (Hash, Recipe) {
+        let path = single_match(
+            self.matching_objects(ObjectClass::Hot, KEY_DIR, KEY_EXTENSION, query),
+            KEY_EXTENSION,
+            query,
+        );
+        read_key(&*self.backend, &path)
+    }
+
+    pub fn remove_key(&self, id: &Hash) {
+        self.backend
+            .remove(ObjectClass::Hot, &object_path(KEY_DIR, id, KEY_EXTENSION));
+    }
+
+    pub fn write_dictionary(&self, dictionary: &Dictionary) -> Hash {
+        self.write_object(
+            DICTIONARY_DIR,
+            DICTIONARY_EXTENSION,
+            &self.subkeys.dictionary,
+            dictionary,
+        )
+    }
+
+    /// Returns all dictionaries in the repository, oldest first. Repositories
+    /// created before dictionaries existed have none.
+    pub fn dictionaries(&self) -> Vec<(Hash, Dictionary)> {
+        let mut dictionaries = list_objects(
+            &*self.backend,
+            ObjectClass::Hot,
+            DICTIONARY_DIR,
+            DICTIONARY_EXTENSION,
+        )
+        .into_iter()
+        .map(|path| self.read_object::<Dictionary>(&path, &self.subkeys.dictionary))
+        .collect::<Vec<_>>();
+
+        dictionaries.sort_by_key(|(_, dictionary)| dictionary.created);
+        dictionaries
+    }
+
+    /// The dictionary new blobs of a class are compressed with, the newest.
+    pub fn current_dictionary(&self, class: BlobClass) -> Option<(Hash, Dictionary)> {
+        self.dictionaries()
+            .into_iter()
+            .rfind(|(_, dictionary)| dictionary.class == class)
+    }
+
+    pub fn remove_dictionary(&self, id: &Hash) {
+        self.backend.remove(
+            ObjectClass::Hot,
+            &object_path(DICTIONARY_DIR, id, DICTIONARY_EXTENSION),
+        );
+        self.dictionaries.borrow_mut().remove(id);
+    }
+
+    fn decompress(&self, kind: BlobKind, data: &[u8], size: usize) -> Vec<u8> {
+        let Some(id) = kind.dictionary() else {
+            return zstd::bulk::decompress(data, size).unwrap();
+        };
+
+        let mut dictionaries = self.dictionaries.borrow_mut();
+        let dictionary = dictionaries.entry(id).or_insert_with(|| {
+            let path = object_path(DICTIONARY_DIR, &id, DICTIONARY_EXTENSION);
+
+            if !self.backend.exists(ObjectClass::Hot, &path) {
+                panic!("dictionary {} is missing", id.to_hex());
+            }
+
+            self.read_object::<Dictionary>(&path, &self.subkeys.dictionary)
+                .1
+                .data
+        });
+
+        zstd::bulk::Decompressor::with_dictionary(dictionary)
+            .unwrap()
+            .decompress(data, size)
+            .unwrap()
+    }
+
+    /// Returns all snapshots in the repository, oldest first.
+    pub fn snapshots(&self) -> Vec<(Hash, Snapshot)> {
+        let mut snapshots = list_objects(
+            &*self.backend,
+            ObjectClass::Hot,
+            SNAPSHOT_DIR,
+            SNAPSHOT_EXTENSION,
+        )
+        .into_iter()
+        .map(|path| self.read_object::<Snapshot>(&path, &self.subkeys.snapshot))
+        .collect::<Vec<_>>();
+
+        snapshots.sort_by_key(|(_, snapshot)| snapshot.time);
+        snapshots
+    }
+
+    /// Resolves a snapshot from either `latest` or a unique prefix of its id.
+    pub fn find_snapshot(&self, query: &str) -> (Hash, Snapshot) {
+        let mut snapshots = self.snapshots();
+
+        if query == "latest" {
+            return snapshots.pop().expect("repository has no snapshots");
+        }
+
+        let mut matches = snapshots
+            .into_iter()
+            .filter(|(id, _)| id.to_hex().starts_with(query));
+
+        match (matches.next(), matches.next()) {
+            (Some(snapshot), None) => snapshot,
+            (None, _) => panic!("no snapshot matching {:?}", query),
+            (Some(_), Some(_)) => panic!("snapshot id {:?} is ambiguous", query),
+        }
+    }
+
+    pub fn locate(&self, id: &Hash) -> Option<BlobLocation> {
+        let blobs = self.blobs.get_or_init(|| {
+            let mut blobs = HashMap::new();
+
+            for index in self.indexes() {
+                for pack in index.packs {
+                    for info in pack.blobs {
+                        blobs.insert(
+                            info.id,
+                            BlobLocation {
+                                pack: pack.id,
+                                info,
+                            },
+                        );
+                    }
+                }
+            }
+
+            blobs
+        });
+
+        blobs.get(id).copied()
+    }
+
+    pub fn read_blob(&self, id: &Hash) -> Vec<u8> {
+        let location = self.locate(id).expect("blob missing from index");
+        let class = location.info.kind.class();
+        let (object_class, dir) = pack_location(class);
+
+        let data = self
+            .backend
+            .read_range(
+                object_class,
+                &object_path(dir, &location.pack, PACK_EXTENSION),
+                location.info.offset as u64,
+                location.info.length + self.cipher().overhead(),
+            )
+            .unwrap();
+
+        let data = unseal_blob(&data, self.pack_key(class));
+        let data = if location.info.kind.is_compressed() {
+            self.decompress(location.info.kind, &data, location.size())
+        } else {
+            data
+        };
+
+        if self.subkeys.blob_id(&data) != *id {
+            panic!("blob {} is corrupt", id.to_hex());
+        }
+
+        data
+    }
+
+    pub fn read_tree(&self, id: &Hash) -> Tree {
+        rmp_serde::from_slice(&self.read_blob(id)).unwrap()
+    }
+
+    /// Reads the content of a file, its inline data if any and then its blobs.
+    pub fn content_reader<'a>(
+        &'a self,
+        content: &'a [Hash],
+        inline: Option<&[u8]>,
+    ) -> ContentReader<'a> {
+        ContentReader {
+            repository: self,
+            blobs: content.iter(),
+            current: inline.map(<[u8]>::to_vec).unwrap_or_default(),
+            position: 0,
+        }
+    }
+
+    /// Finds packs which made it to storage but were never referenced by an
+    /// index, typically because a backup was interrupted before it could flush
+    /// its index. Packs are told apart by the ids in their names, so only the
+    /// unindexed ones are read, and their blob layout is recovered from their
+    /// headers. Cold packs are never read: they are found through their hot
+    /// header copies and only checked to be stored in full. Header copies of
+    /// cold packs which never made it to storage are removed.
+    pub fn recover_packs(&self, indexed: &HashSet<Hash>) -> Vec<IndexPackInfo> {
+        let mut recovered = Vec::new();
+
+        for class in BlobClass::ALL {
+            let (object_class, dir) = pack_location(class);
+            let cold = object_class == ObjectClass::Cold;
+            let paths = if cold {
+                list_objects(
+                    &*self.backend,
+                    ObjectClass::Hot,
+                    HEADER_DIR,
+                    HEADER_EXTENSION,
+                )
+            } else {
+                list_objects(&*self.backend, object_class, dir, PACK_EXTENSION)
+            };
+
+            for path in paths {
+                let Some(id) = object_id(&path) else {
+                    log::warn!("ignoring pack with a malformed name {:?}", path);
+                    continue;
+                };
+
+                if indexed.contains(&id) {
+                    continue;
+                }
+
+                let data = if cold {
+                    self.backend.read(ObjectClass::Hot, &path).unwrap()
+                } else {
+                    let data = self.backend.read(object_class, &path).unwrap();
+                    if Hash::from(blake3::hash(&data)) != id {
+                        log::warn!("ignoring pack {:?} whose contents don't match its id", path);
+                        continue;
+                    }
+                    data
+                };
+
+                let Some(info) = read_pack_info(&data, self.pack_key(class), self.cipher()) else {
+                    log::warn!("ignoring unreadable pack {:?}", path);
+                    continue;
+                };
+
+                let pack = pack::index_pack_info(id, &info.blobs, self.cipher());
+
+                if cold {
+                    // The blobs, then the header as copied.
+                    let size = pack.blobs.last().map_or(0, |blob| {
+                        blob.offset + blob.length + self.cipher().overhead()
+                    }) + data.len();
+                    let pack_path = object_path(dir, &id, PACK_EXTENSION);
+
+                    match self.backend.size(object_class, &pack_path) {
+                        Ok(stored) if stored == size as u64 => {}
+                        Ok(stored) => {
+                            log::warn!(
+                                "ignoring cold pack {:?} of {} bytes, its header says {}",
+                                pack_path,
+                                stored,
+                                size
+                            );
+                            continue;
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                            log::warn!("removing header copy {:?} of a pack never stored", path);
+                            self.backend.remove(ObjectClass::Hot, &path);
+                            continue;
+                        }
+                        Err(err) => panic!("failed to stat {:?}: {}", pack_path, err),
+                    }
+                }
+
+                recovered.push(pack);
+            }
+        }
+
+        recovered
+    }
+
+    /// Resolves a unique prefix of a pack id and reads the header of that pack.
+    pub fn find_pack(&self, query: &str) -> (Hash, PackInfo) {
+        let matches = BlobClass::ALL
+            .into_iter()
+            .flat_map(|class| {
+                let (object_class, dir) = pack_location(class);
+                self.matching_objects(object_class, dir, PACK_EXTENSION, query)
+                    .into_iter()
+                    .map(move |path| (class, path))
+            })
+            .collect();
+        let (class, path) = single_match(matches, PACK_EXTENSION, query);
+
+        let data = self.backend.read(pack_location(class).0, &path).unwrap();
+        let id = Hash::from(blake3::hash(&data));
+
+        let info = read_pack_info(&data, self.pack_key(class), self.cipher())
+            .expect("pack header is unreadable");
+        (id, info)
+    }
+
+    /// Lists the objects in a directory whose id starts with a query.
+    fn matching_objects(
+        &self,
+        class: ObjectClass,
+        dir: &str,
+        extension: &str,
+        query: &str,
+    ) -> Vec<String> {
+        list_objects(&*self.backend, class, dir, extension)
+            .into_iter()
+            .filter(|path| {
+                path.rsplit('/')
+                    .next()
+                    .is_some_and(|name| name.starts_with(query))
+            })
+            .collect()
+    }
+}
+
+pub struct ContentReader<'a> {
+    repository: &'a Repository,
+    blobs: std::slice::Iter<'a, Hash>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ContentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.current.len() {
+            let Some(id) = self.blobs.next() else {
+                return Ok(0);
+            };
+
+            self.current = self.repository.read_blob(id);
+            self.position = 0;
+        }
+
+        let len = buf.len().min(self.current.len() - self.position);
+        buf[..len].copy_from_slice(&self.current[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+fn read_pack_info(data: &[u8], key: &Key, cipher: Cipher) -> Option<PackInfo> {
+    let len_start = data.len().checked_sub(4)?;
+    let header_len = u32::from_le_bytes(data[len_start..].try_into().unwrap()) as usize;
+    let header_start = len_start.checked_sub(header_len)?;
+    let header = &data[header_start..len_start];
+
+    if header.len() < cipher.overhead() {
+        return None;
+    }
+
+    rmp_serde::from_slice(&unseal_blob(header, key)).ok()
+}
+
+/// Where the packs of a blob class are stored. Tree packs are hot, since
+/// every listing reads them, while data packs are only read for file contents.
+fn pack_location(class: BlobClass) -> (ObjectClass, &'static str) {
+    match class {
+        BlobClass::Tree => (ObjectClass::Hot, TREE_DIR),
+        BlobClass::Data => (ObjectClass::Cold, DATA_DIR),
+    }
+}
+
+fn object_path(dir: &str, id: &Hash, extension: &str) -> String {
+    format!("{}/{}.{}", dir, id.to_hex(), extension)
+}
+
+/// Parses the id out of an object path made by [`object_path`].
+fn object_id(path: &str) -> Option<Hash> {
+    Hash::from_hex(Path::new(path).file_stem()?.to_str()?)
+}
+
+fn read_key(backend: &dyn Backend, path: &str) -> (Hash, Recipe) {
+    let data = backend.read(ObjectClass::Hot, path).unwrap();
+    let id = Hash::from(blake3::hash(&data));
+    (id, serde_json::from_slice(&data).unwrap())
+}
+
+fn single_match<T>(matches: Vec<T>, extension: &str, query: &str) -> T {
+    let mut matches = matches.into_iter();
+
+    match (matches.next(), matches.next()) {
+        (Some(object), None) => object,
+        (None, _) => panic!("no {} matching {:?}", extension, query),
+        (Some(_), Some(_)) => panic!("{} id {:?} is ambiguous", extension, query),
+    }
+}
+
+/// Lists the paths of the objects with an extension in a directory, sorted.
+fn list_objects(
+    backend: &dyn Backend,
+    class: ObjectClass,
+    dir: &str,
+    extension: &str,
+) -> Vec<String> {
+    backend
+        .list(class, dir)
+        .into_iter()
+        .filter(|name| {
+            Path::new(name)
+                .extension()
+                .is_some_and(|ext| ext == extension)
+        })
+        .map(|name| format!("{}/{}", dir, name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::cmd::{backup, diff, find, ls};
+    use crate::pack::Compression;
+
+    /// Fails on any access to cold objects, as archived storage would.
+    struct HotOnly(LocalBackend);
+
+    impl HotOnly {
+        fn check(class: ObjectClass, path: &str) {
+            assert_eq!(class, ObjectClass::Hot, "touched cold object {}", path);
+        }
+    }
+
+    impl Backend for HotOnly {
+        fn exists(&self, class: ObjectClass, path: &str) -> bool {
+            Self::check(class, path);
+            self.0.exists(class, path)
+        }
+
+        fn read(&self, class: ObjectClass, path: &str) -> io::Result<Vec<u8>> {
+            Self::check(class, path);
+            self.0.read(class, path)
+        }
+
+        fn read_range(
+            &self,
+            class: ObjectClass,
+            path: &str,
+            offset: u64,
+            length: usize,
+        ) -> io::Result<Vec<u8>> {
+            Self::check(class, path);
+            self.0.read_range(class, path, offset, length)
+        }
+
+        fn size(&self, class: ObjectClass, path: &str) -> io::Result<u64> {
+            Self::check(class, path);
+            self.0.size(class, path)
+        }
+
+        fn write(&self, class: ObjectClass, path: &str, data: &[u8]) {
+            Self::check(class, path);
+            self.0.write(class, path, data)
+        }
+
+        fn list(&self, class: ObjectClass, dir: &str) -> Vec<String> {
+            Self::check(class, dir);
+            self.0.list(class, dir)
+        }
+
+        fn remove(&self, class: ObjectClass, path: &str) {
+            Self::check(class, path);
+            self.0.remove(class, path)
+        }
+    }
+
+    #[test]
+    fn test_browse_without_cold_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, root) = (dir.path().join("source"), dir.path().join("repo"));
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/a.txt"), vec![1; 100_000]).unwrap();
+        fs::write(source.join("b.txt"), b"before").unwrap();
+
+        let key = Key::generate();
+        let repository = Repository::init(
+            &root,
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            Some(PathBuf::from("cold")),
+        );
+
+        let options = backup::Options {
+            compression: Compression::Auto,
+            inline_size: 0,
+            deterministic: false,
+        };
+        backup::run(&repository, &source, options);
+        fs::write(source.join("b.txt"), b"after").unwrap();
+        backup::run(&repository, &source, options);
+
+        assert!(
+            !list_objects(
+                &LocalBackend::new(&root, &root.join("cold")),
+                ObjectClass::Cold,
+                DATA_DIR,
+                PACK_EXTENSION
+            )
+            .is_empty()
+        );
+        assert!(!fs::exists(root.join(DATA_DIR)).unwrap());
+
+        let backend = HotOnly(LocalBackend::new(&root, &root.join("cold")));
+        let repository = Repository::with_backend(Box::new(backend), key);
+        let snapshots = repository
+            .snapshots()
+            .into_iter()
+            .map(|(id, _)| id.to_hex())
+            .collect::<Vec<_>>();
+
+        ls::run(&repository, &snapshots[0], None, true, ls::Format::Long);
+        find::run(
+            &repository,
+            &snapshots,
+            &find::Filter {
+                pattern: Some("*.txt".to_string()),
+                newer: None,
+                older: None,
+                min_size: Some(1),
+                max_size: None,
+                blobs: Vec::new(),
+                trees: Vec::new(),
+            },
+        );
+        diff::run(&repository, &snapshots[0], &snapshots[1], false);
+    }
+
+    #[test]
+    fn test_upgrade_on_first_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Key::generate();
+        let repository = Repository::init(
+            dir.path(),
+            key,
+            Cipher::default(),
+            ChunkerParams::default(),
+            PackSize::default(),
+            None,
+        );
+
+        let config = Config {
+            version: RepositoryVersion::V3,
+            ..repository.config().clone()
+        };
+        let data = seal_blob(&rmp_serde::to_vec(&config).unwrap(), &key, config.cipher);
+        fs::write(dir.path().join(CONFIG_FILE), data).unwrap();
+
+        let version = || i32::from(Repository::open(dir.path(), key).config().version);
+        let repository = Repository::open(dir.path(), key);
+        assert_eq!(version(), 3);
+
+        repository.write_pack(BlobClass::Tree, Hash::from(blake3::hash(b"tree")), b"tree");
+        assert_eq!(version(), 4);
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::BTreeSet;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -21,32 +22,109 @@ pub struct PackInfoEntry {
     pub kind: BlobKind,
     #[serde(rename = "u")]
     pub size_uncompressed: usize,
-    #[serde(rename = "c", skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "c", default, skip_serializing_if = "Option::is_none")]
     pub size_compressed: Option<NonZeroUsize>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-#[serde(into = "i32", from = "i32")]
-#[repr(i32)]
+/// Compressed kinds carry the zstd level they were compressed with, which is
+/// encoded by adding it to a base per kind. Kinds compressed with a trained
+/// dictionary are stored along with the id of that dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "RawBlobKind", from = "RawBlobKind")]
 pub enum BlobKind {
-    Tree = 1,
-    Data = 2,
-    DataZstd3 = 3,
+    Tree,
+    Data,
+    TreeZstd(i32),
+    DataZstd(i32),
+    TreeZstdDictionary(i32, Hash),
+    DataZstdDictionary(i32, Hash),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawBlobKind {
+    Plain(i32),
+    Dictionary(i32, Hash),
 }
 
-impl From<BlobKind> for i32 {
+const BLOB_KIND_TREE: i32 = 1;
+const BLOB_KIND_DATA: i32 = 2;
+/// Data compressed at the default level, from before the level was recorded.
+const BLOB_KIND_DATA_ZSTD3: i32 = 3;
+const BLOB_KIND_TREE_ZSTD: i32 = 0x100;
+const BLOB_KIND_DATA_ZSTD: i32 = 0x200;
+const BLOB_KIND_TREE_ZSTD_DICTIONARY: i32 = 0x300;
+const BLOB_KIND_DATA_ZSTD_DICTIONARY: i32 = 0x400;
+const BLOB_KIND_LEVELS: std::ops::RangeInclusive<i32> = 1..=0xff;
+
+impl BlobKind {
+    pub fn class(self) -> BlobClass {
+        match self {
+            BlobKind::Tree | BlobKind::TreeZstd(_) | BlobKind::TreeZstdDictionary(..) => {
+                BlobClass::Tree
+            }
+            BlobKind::Data | BlobKind::DataZstd(_) | BlobKind::DataZstdDictionary(..) => {
+                BlobClass::Data
+            }
+        }
+    }
+
+    pub fn is_compressed(self) -> bool {
+        !matches!(self, BlobKind::Tree | BlobKind::Data)
+    }
+
+    /// The dictionary needed to decompress blobs of this kind, if any.
+    pub fn dictionary(self) -> Option<Hash> {
+        match self {
+            BlobKind::TreeZstdDictionary(_, id) | BlobKind::DataZstdDictionary(_, id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl From<BlobKind> for RawBlobKind {
     fn from(value: BlobKind) -> Self {
-        value as i32
+        match value {
+            BlobKind::Tree => RawBlobKind::Plain(BLOB_KIND_TREE),
+            BlobKind::Data => RawBlobKind::Plain(BLOB_KIND_DATA),
+            BlobKind::TreeZstd(level) => RawBlobKind::Plain(BLOB_KIND_TREE_ZSTD + level),
+            BlobKind::DataZstd(level) => RawBlobKind::Plain(BLOB_KIND_DATA_ZSTD + level),
+            BlobKind::TreeZstdDictionary(level, id) => {
+                RawBlobKind::Dictionary(BLOB_KIND_TREE_ZSTD_DICTIONARY + level, id)
+            }
+            BlobKind::DataZstdDictionary(level, id) => {
+                RawBlobKind::Dictionary(BLOB_KIND_DATA_ZSTD_DICTIONARY + level, id)
+            }
+        }
     }
 }
 
-impl From<i32> for BlobKind {
-    fn from(value: i32) -> Self {
+impl From<RawBlobKind> for BlobKind {
+    fn from(value: RawBlobKind) -> Self {
+        let level = |v: i32, base| BLOB_KIND_LEVELS.contains(&(v - base)).then_some(v - base);
+
         match value {
-            v if v == BlobKind::Tree as i32 => BlobKind::Tree,
-            v if v == BlobKind::Data as i32 => BlobKind::Data,
-            v if v == BlobKind::DataZstd3 as i32 => BlobKind::DataZstd3,
-            _ => panic!(),
+            RawBlobKind::Plain(BLOB_KIND_TREE) => BlobKind::Tree,
+            RawBlobKind::Plain(BLOB_KIND_DATA) => BlobKind::Data,
+            RawBlobKind::Plain(BLOB_KIND_DATA_ZSTD3) => BlobKind::DataZstd(3),
+            RawBlobKind::Plain(v) => {
+                if let Some(level) = level(v, BLOB_KIND_TREE_ZSTD) {
+                    BlobKind::TreeZstd(level)
+                } else if let Some(level) = level(v, BLOB_KIND_DATA_ZSTD) {
+                    BlobKind::DataZstd(level)
+                } else {
+                    panic!("unknown blob kind {}", v)
+                }
+            }
+            RawBlobKind::Dictionary(v, id) => {
+                if let Some(level) = level(v, BLOB_KIND_TREE_ZSTD_DICTIONARY) {
+                    BlobKind::TreeZstdDictionary(level, id)
+                } else if let Some(level) = level(v, BLOB_KIND_DATA_ZSTD_DICTIONARY) {
+                    BlobKind::DataZstdDictionary(level, id)
+                } else {
+                    panic!("unknown blob kind {}", v)
+                }
+            }
         }
     }
 }
@@ -94,9 +172,24 @@ impl Ord for Node {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "lowercase")]
 pub enum NodeKind {
-    File { content: Vec<Hash> },
-    Dir { subtree: Hash },
-    Symlink { link_target: UPath, links: u64 },
+    File {
+        content: Vec<Hash>,
+        /// Content of a file small enough to be kept in its tree rather than
+        /// in blobs of its own, in which case `content` is empty.
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+        inline: Option<Vec<u8>>,
+    },
+    Dir {
+        subtree: Hash,
+    },
+    Symlink {
+        link_target: UPath,
+        links: u64,
+    },
+    /// A device node, fifo or socket, told apart by the file type in the mode.
+    Special {
+        rdev: u64,
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -138,7 +231,7 @@ pub struct IndexBlobInfo {
     pub kind: BlobKind,
     pub offset: usize,
     pub length: usize,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub length_uncompressed: Option<NonZeroUsize>,
 }
 
@@ -162,6 +255,8 @@ pub enum Kdf {
         r: i32,
         p: i32,
     },
+    /// Memory cost in KiB, number of passes and degree of parallelism.
+    Argon2id { m: u32, t: u32, p: u32 },
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -179,20 +274,115 @@ pub struct Snapshot {
     pub uid: u32,
     pub gid: u32,
     pub tags: Vec<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
     pub original: Option<Hash>,
+    /// Metadata of the directory backed up, whose contents are `tree`. Older
+    /// snapshots and those of streams have none.
+    #[serde(default)]
+    pub root: Option<Node>,
+}
+
+/// A zstd dictionary trained from blobs of a class which compress poorly on
+/// their own. The newest dictionary of each class is used for new blobs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dictionary {
+    pub class: BlobClass,
+    pub created: i64,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// What blobs hold, which decides the packs they go to and where those packs
+/// are stored. Dictionaries are trained per class too, data dictionaries only
+/// from blobs small enough to be whole small files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobClass {
+    Tree,
+    Data,
+}
+
+impl BlobClass {
+    pub const ALL: [BlobClass; 2] = [BlobClass::Tree, BlobClass::Data];
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub version: RepositoryVersion,
     pub id: Uuid,
+    /// Secret which permutes the gear table of the chunker.
+    #[serde(default)]
+    pub chunker_seed: [u8; 32],
+    #[serde(default)]
+    pub cipher: Cipher,
+    /// Chunk sizes every client writing to the repository splits files into.
+    #[serde(default)]
+    pub chunker: ChunkerParams,
+    /// Sizes packs are written at, which suit the backend the repository is
+    /// stored on.
+    #[serde(default)]
+    pub pack_size: PackSize,
+    /// Directory data packs are kept in instead of the repository, relative to
+    /// it, for storage which is slow or costly to read from.
+    #[serde(default)]
+    pub cold_storage: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackSize {
+    /// Size at which a pack is finished.
+    pub target: usize,
+    /// Size no pack grows beyond, unless it holds a single larger blob.
+    pub max: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkerParams {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+    /// FastCDC normalization level, from 0 to 3.
+    pub level: u32,
+    #[serde(default)]
+    pub algorithm: ChunkerAlgorithm,
+}
+
+/// Algorithm files are split into chunks with. Repositories from before it was
+/// configurable use `FastCdc2020`. `Buzhash` ignores the normalization level,
+/// and `Fixed` the minimum and maximum size, cutting every average size bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkerAlgorithm {
+    #[default]
+    #[serde(rename = "fastcdc-2020")]
+    FastCdc2020,
+    #[serde(rename = "fastcdc-2016")]
+    FastCdc2016,
+    #[serde(rename = "buzhash")]
+    Buzhash,
+    #[serde(rename = "fixed")]
+    Fixed,
+}
+
+/// Envelope and algorithm blobs are sealed with. Repositories from before the
+/// envelope was versioned have no cipher in their config and use `Legacy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    #[default]
+    #[serde(rename = "legacy")]
+    Legacy,
+    #[serde(rename = "chacha12-blake3")]
+    ChaCha12Blake3,
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[repr(i32)]
 pub enum RepositoryVersion {
     V1 = 1,
+    V2 = 2,
+    V3 = 3,
+    V4 = 4,
 }
 
 impl From<RepositoryVersion> for i32 {
@@ -205,6 +395,9 @@ impl From<i32> for RepositoryVersion {
     fn from(value: i32) -> Self {
         match value {
             1 => RepositoryVersion::V1,
+            2 => RepositoryVersion::V2,
+            3 => RepositoryVersion::V3,
+            4 => RepositoryVersion::V4,
             _ => panic!(),
         }
     }
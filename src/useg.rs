@@ -1,15 +1,21 @@
 use std::borrow::Borrow;
 use std::ffi::OsStr;
+use std::fmt::{self, Write};
 use std::path::Path;
 
-use serde::{Deserialize, Serialize};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+/// Raw bytes of the components of a path, with the offset each component ends
+/// at. Repositories before version 4 stored the offsets as single bytes, which
+/// wrapped around for paths longer than 255 bytes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 pub struct UPath {
     #[serde(with = "serde_bytes")]
     buffer: Box<[u8]>,
-    #[serde(with = "serde_bytes")]
-    splits: Box<[u8]>,
+    #[serde(deserialize_with = "deserialize_splits")]
+    splits: Box<[u32]>,
 }
 
 impl UPath {
@@ -18,7 +24,7 @@ impl UPath {
         let mut splits = Vec::new();
         for component in path.components() {
             buf.extend_from_slice(normalize_osstr(component.as_os_str()));
-            splits.push(buf.len() as u8);
+            splits.push(u32::try_from(buf.len()).expect("path is longer than 4 GiB"));
         }
 
         let buffer = buf.into_boxed_slice();
@@ -26,30 +32,114 @@ impl UPath {
         Self { buffer, splits }
     }
 
-    pub fn parent(&self) -> Self {
-        let mut new_splits = Vec::new();
-        for i in 0..self.splits.len() - 1 {
-            new_splits.push(self.splits[i]);
+    /// The path without its last component, like [`Path::parent`], so `None`
+    /// for the root and the empty path.
+    pub fn parent(&self) -> Option<Self> {
+        let (_, splits) = self.splits.split_last()?;
+        if self.last_segment() == b"/" {
+            return None;
         }
 
-        let cut = new_splits.last().unwrap();
-        let buffer = &self.buffer[..*cut as usize];
-        let buffer = buffer.to_vec().into_boxed_slice();
-        let new_splits = new_splits.into_boxed_slice();
-        Self {
-            buffer,
-            splits: new_splits,
-        }
+        let cut = splits.last().map_or(0, |&cut| cut as usize);
+        Some(Self {
+            buffer: self.buffer[..cut].into(),
+            splits: splits.into(),
+        })
     }
 
     pub fn last_segment(&self) -> &[u8] {
-        let last = self.splits.last().unwrap();
-        let start = self.splits.get(self.splits.len() - 2).unwrap_or(&0);
-        &self.buffer[*start as usize..*last as usize]
+        let (&end, splits) = self.splits.split_last().unwrap();
+        let start = splits.last().map_or(0, |&start| start as usize);
+        &self.buffer[start..end as usize]
+    }
+
+    /// Joins the segments back into the raw bytes of a unix style path.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.buffer.len() + self.splits.len());
+        let mut separate = false;
+
+        for segment in self.segments() {
+            if separate {
+                bytes.push(b'/');
+            }
+
+            bytes.extend_from_slice(segment);
+            separate = segment != b"/";
+        }
+
+        bytes
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        let starts = std::iter::once(0).chain(self.splits.iter().copied());
+        starts
+            .zip(self.splits.iter().copied())
+            .map(|(start, end)| &self.buffer[start as usize..end as usize])
+    }
+}
+
+/// Reads the offsets of a path as written since repository version 4, an
+/// array of integers, or before it, a byte string of single byte offsets.
+fn deserialize_splits<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Box<[u32]>, D::Error> {
+    struct Splits;
+
+    impl<'de> Visitor<'de> for Splits {
+        type Value = Box<[u32]>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("path component offsets")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(bytes.iter().map(|&split| split as u32).collect())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut splits = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(split) = seq.next_element()? {
+                splits.push(split);
+            }
+
+            Ok(splits.into_boxed_slice())
+        }
+    }
+
+    deserializer.deserialize_any(Splits)
+}
+
+/// Human readable formats get the escaped display form, which is meant for
+/// output only and can't be deserialized back.
+impl Serialize for UPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return serializer.collect_str(self);
+        }
+
+        let mut state = serializer.serialize_struct("UPath", 2)?;
+        state.serialize_field("buffer", serde_bytes::Bytes::new(&self.buffer))?;
+        state.serialize_field("splits", &self.splits)?;
+        state.end()
+    }
+}
+
+impl fmt::Display for UPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separate = false;
+
+        for segment in self.segments() {
+            if separate {
+                f.write_str("/")?;
+            }
+
+            write_escaped(f, segment)?;
+            separate = segment != b"/";
+        }
+
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 pub struct USeg {
     #[serde(with = "serde_bytes")]
     raw: Box<[u8]>,
@@ -60,6 +150,33 @@ impl USeg {
         let raw = bytes.to_vec().into_boxed_slice();
         Self { raw }
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+}
+
+/// Displays the segment as UTF-8, with any bytes that aren't valid UTF-8
+/// escaped as `\xNN`. Control characters and backslashes are escaped too, so
+/// a name can't pass itself off as other output.
+impl fmt::Display for USeg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_escaped(f, &self.raw)
+    }
+}
+
+/// Human readable formats get the escaped display form, which is meant for
+/// output only and can't be deserialized back.
+impl Serialize for USeg {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return serializer.collect_str(self);
+        }
+
+        let mut state = serializer.serialize_struct("USeg", 1)?;
+        state.serialize_field("raw", serde_bytes::Bytes::new(&self.raw))?;
+        state.end()
+    }
 }
 
 impl Borrow<[u8]> for USeg {
@@ -68,6 +185,25 @@ impl Borrow<[u8]> for USeg {
     }
 }
 
+fn write_escaped(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                c if c.is_ascii_control() => write!(f, "\\x{:02x}", c as u32)?,
+                c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+
+        for byte in chunk.invalid() {
+            write!(f, "\\x{:02x}", byte)?;
+        }
+    }
+
+    Ok(())
+}
+
 fn normalize_osstr(s: &OsStr) -> &[u8] {
     #[cfg(target_family = "unix")]
     {
@@ -78,3 +214,103 @@ fn normalize_osstr(s: &OsStr) -> &[u8] {
     #[cfg(not(target_family = "unix"))]
     s.to_str().expect("found bad byte in path").as_bytes()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Unix paths of raw bytes, absolute or relative, with `.`, `..`, empty
+    /// and non-UTF-8 components, often longer than 255 bytes.
+    fn path() -> impl Strategy<Value = PathBuf> {
+        let name =
+            prop::collection::vec(any::<u8>().prop_filter("separator", |&b| b != b'/'), 1..40);
+        let component = prop_oneof![
+            Just(b".".to_vec()),
+            Just(b"..".to_vec()),
+            Just(Vec::new()),
+            name.clone(),
+            name,
+        ];
+
+        (any::<bool>(), prop::collection::vec(component, 0..30)).prop_map(
+            |(absolute, components)| {
+                let mut bytes = if absolute { b"/".to_vec() } else { Vec::new() };
+                bytes.extend(components.join(&b'/'));
+                PathBuf::from(OsStr::from_bytes(&bytes))
+            },
+        )
+    }
+
+    fn bytes(path: &Path) -> &[u8] {
+        path.as_os_str().as_bytes()
+    }
+
+    proptest! {
+        #[test]
+        fn test_components(path in path()) {
+            let upath = UPath::from_path(&path);
+            let components = path.components().collect::<Vec<_>>();
+
+            let segments = upath.segments().collect::<Vec<_>>();
+            let expected = components.iter().map(|c| bytes(c.as_ref())).collect::<Vec<_>>();
+            prop_assert_eq!(segments, expected);
+
+            let normalized = components.iter().collect::<PathBuf>();
+            prop_assert_eq!(upath.to_bytes(), bytes(&normalized));
+
+            if let Some(last) = components.last() {
+                prop_assert_eq!(upath.last_segment(), bytes(last.as_ref()));
+            }
+        }
+
+        #[test]
+        fn test_parent(path in path()) {
+            let upath = UPath::from_path(&path);
+            prop_assert_eq!(upath.parent(), path.parent().map(UPath::from_path));
+        }
+
+        #[test]
+        fn test_serialization_roundtrip(path in path()) {
+            let upath = UPath::from_path(&path);
+            let data = rmp_serde::to_vec(&upath).unwrap();
+            prop_assert_eq!(rmp_serde::from_slice::<UPath>(&data).unwrap(), upath);
+        }
+    }
+
+    #[test]
+    fn test_display_escapes() {
+        let segment = USeg::from_segment_bytes(b"a\\b\n\x1b[1m\xc2\x85\xff\xc3\xa9");
+        assert_eq!(segment.to_string(), "a\\\\b\\x0a\\x1b[1m\\u{85}\\xffé");
+
+        let upath = UPath::from_path(Path::new("/tab\there/back\\slash"));
+        assert_eq!(upath.to_string(), "/tab\\x09here/back\\\\slash");
+    }
+
+    #[test]
+    fn test_long_path() {
+        let name = "x".repeat(200);
+        let path = PathBuf::from(format!("/{}/{}/{}", name, name, name));
+        let upath = UPath::from_path(&path);
+
+        assert_eq!(upath.last_segment(), name.as_bytes());
+        assert_eq!(upath.parent().unwrap().to_bytes().len(), 1 + 200 + 1 + 200);
+        assert_eq!(upath.to_bytes(), bytes(&path));
+    }
+
+    #[test]
+    fn test_byte_splits() {
+        let legacy = rmp_serde::to_vec(&(
+            serde_bytes::Bytes::new(b"/homeuser"),
+            serde_bytes::Bytes::new(&[1, 5, 9]),
+        ))
+        .unwrap();
+
+        let upath: UPath = rmp_serde::from_slice(&legacy).unwrap();
+        assert_eq!(upath, UPath::from_path(Path::new("/home/user")));
+    }
+}